@@ -1,6 +1,7 @@
-use tonic_build;
-
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/weve_market.proto")?;
+    // Older protoc releases only accept proto3 optional fields behind a flag
+    tonic_build::configure()
+        .protoc_arg("--experimental_allow_proto3_optional")
+        .compile(&["proto/weve_market.proto"], &["proto"])?;
     Ok(())
 }
\ No newline at end of file
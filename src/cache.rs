@@ -5,6 +5,7 @@ use std::collections::HashMap;
 pub struct Cache<K, V> {
     inner: HashMap<K, V>,
     expiry: u64,
+    refreshing: bool,
 }
 
 impl<K: Eq + std::hash::Hash, V> Cache<K, V> {
@@ -12,13 +13,7 @@ impl<K: Eq + std::hash::Hash, V> Cache<K, V> {
         Cache {
             inner: HashMap::new(),
             expiry: 0,
-        }
-    }
-
-    pub fn get(&self, k: &K) -> Option<&V> {
-        match &time::now() < &self.expiry {
-            true => self.inner.get(k),
-            false => None,
+            refreshing: false,
         }
     }
 
@@ -26,18 +21,50 @@ impl<K: Eq + std::hash::Hash, V> Cache<K, V> {
         self.inner.get(k)
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.inner.iter()
+    }
+
     pub fn insert(&mut self, k: K, v: V) {
         self.inner.insert(k, v);
     }
 
-    pub fn clear_and_update_expiry(&mut self, expiry: u64) {
-        self.inner.clear();
+    // Returns the previous contents
+    pub fn clear_and_update_expiry(&mut self, expiry: u64) -> HashMap<K, V> {
+        self.expiry = expiry;
+        std::mem::take(&mut self.inner)
+    }
+
+    // Keeps the contents, for when ESI reports they have not changed
+    pub fn update_expiry(&mut self, expiry: u64) {
         self.expiry = expiry;
     }
 
+    pub fn expiry(&self) -> u64 {
+        self.expiry
+    }
+
     pub fn expired(&self) -> bool {
         time::now() > self.expiry
     }
+
+    // Returns true if the cache has expired, but was filled at some point
+    // and expired no more than max_staleness seconds ago
+    pub fn stale(&self, max_staleness: u64) -> bool {
+        self.expiry != 0
+            && self.expired()
+            && time::now() <= self.expiry + max_staleness
+    }
+
+    // Returns true if the caller should start a refresh, false if one is
+    // already running
+    pub fn begin_refresh(&mut self) -> bool {
+        !std::mem::replace(&mut self.refreshing, true)
+    }
+
+    pub fn end_refresh(&mut self) {
+        self.refreshing = false;
+    }
 }
 
 // impl Cache<crate::proto::AdjustedPriceReq, crate::proto::AdjustedPriceRep>{
@@ -1,11 +1,12 @@
 use crate::{
-    {LocationId, RegionId, MarketName, RefreshToken},
+    {LocationId, RegionId, MarketName, RefreshToken, TypeId},
     time,
 };
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use either::Either;
+use rand::Rng;
 
 #[derive(Debug, Default, Clone)]
 pub struct Markets {
@@ -23,6 +24,57 @@ pub struct MinCacheDuration {
     pub system_index: u64,
 }
 
+#[derive(Debug, Default, Clone)]
+pub struct Prefetch {
+    pub enabled: bool,
+    pub jitter: u64,
+    pub watchlist: Vec<(RegionId, TypeId)>,
+}
+
+// The solar system jump graph and the systems of station markets, used to
+// find buy orders elsewhere in a region whose range reaches a station
+#[derive(Debug, Default, Clone)]
+pub struct BuyRange {
+    pub jumps: HashMap<i32, Vec<i32>>,
+    pub station_systems: HashMap<LocationId, i32>,
+}
+
+// Seconds history rows are kept as recorded, the resolution in seconds they
+// are then averaged into, and the seconds any row is kept at all
+#[derive(Debug, Clone)]
+pub struct HistoryRetention {
+    pub raw: u64,
+    pub resolution: u64,
+    pub retention: u64,
+}
+
+// Thresholds of the ESI error budget below which requests are slowed or
+// paused, and the most ESI requests that may be in flight at once
+#[derive(Debug, Clone)]
+pub struct ErrorLimit {
+    pub max_concurrent_requests: usize,
+    pub slow_below: u32,
+    pub pause_below: u32,
+}
+
+// How often and how patiently idempotent ESI requests are retried after a
+// transient failure, with delays in milliseconds
+#[derive(Debug, Clone)]
+pub struct Retry {
+    pub attempts: usize,
+    pub base_delay: u64,
+    // Also caps the delays ESI asks for with Retry-After
+    pub max_delay: u64,
+}
+
+// Base URLs every ESI and SSO endpoint is derived from
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    esi_url: String,
+    sso_url: String,
+    datasource: String,
+}
+
 impl Markets {
     pub fn with_capacity(capacity: usize) -> Markets {
         Markets {
@@ -53,6 +105,14 @@ impl Markets {
         self.inner.values()
     }
 
+    pub fn iter(
+        &self,
+    ) -> impl Iterator<
+        Item = (&MarketName, &(LocationId, Either<RegionId, Option<RefreshToken>>))
+    > {
+        self.inner.iter()
+    }
+
     // Returns an iterator over all locationid which are structures
     // pub fn structure_ids(&self) -> impl Iterator<Item = LocationId> + '_ {
     //     self.inner
@@ -119,3 +179,143 @@ impl MinCacheDuration {
         time::now() + self.system_index
     }
 }
+
+impl Prefetch {
+    // Returns a random delay of up to jitter seconds
+    pub fn jitter(&self) -> u64 {
+        rand::thread_rng().gen_range(0..=self.jitter)
+    }
+}
+
+impl BuyRange {
+    // Returns the number of jumps from the system to every system at most
+    // max_jumps away, the system itself included
+    pub fn distances(&self, from: i32, max_jumps: u32) -> HashMap<i32, u32> {
+        let mut distances: HashMap<i32, u32> = HashMap::from([(from, 0)]);
+        let mut queue: VecDeque<i32> = VecDeque::from([from]);
+        while let Some(system) = queue.pop_front() {
+            let jumps: u32 = distances[&system];
+            if jumps >= max_jumps {
+                continue;
+            }
+            for next in self.jumps.get(&system).into_iter().flatten() {
+                if !distances.contains_key(next) {
+                    distances.insert(*next, jumps + 1);
+                    queue.push_back(*next);
+                }
+            }
+        }
+        distances
+    }
+}
+
+impl Retry {
+    // Exponential backoff for the given attempt, starting at 1, with up to
+    // half of the delay randomized away so retries do not line up
+    pub fn delay(&self, attempt: usize) -> std::time::Duration {
+        let delay: u64 = self.base_delay
+            .saturating_mul(1 << (attempt - 1).min(32))
+            .min(self.max_delay);
+        std::time::Duration::from_millis(
+            delay - rand::thread_rng().gen_range(0..=delay / 2),
+        )
+    }
+}
+
+impl Endpoints {
+    pub fn new(esi_url: &str, sso_url: &str, datasource: &str) -> Endpoints {
+        Endpoints {
+            esi_url: esi_url.trim_end_matches('/').to_string(),
+            sso_url: sso_url.trim_end_matches('/').to_string(),
+            datasource: datasource.to_string(),
+        }
+    }
+
+    pub fn esi_url(&self) -> &str {
+        &self.esi_url
+    }
+
+    pub fn sso_url(&self) -> &str {
+        &self.sso_url
+    }
+
+    pub fn datasource(&self) -> &str {
+        &self.datasource
+    }
+
+    pub fn adjusted_price_url(&self) -> String {
+        format!("{}/markets/prices/", self.esi_url)
+    }
+
+    pub fn system_index_url(&self) -> String {
+        format!("{}/industry/systems/", self.esi_url)
+    }
+
+    pub fn station_order_url(&self, region_id: &RegionId) -> String {
+        format!("{}/markets/{}/orders/", self.esi_url, region_id)
+    }
+
+    pub fn market_history_url(&self, region_id: &RegionId) -> String {
+        format!("{}/markets/{}/history/", self.esi_url, region_id)
+    }
+
+    pub fn structure_order_url(&self, location_id: &LocationId) -> String {
+        format!("{}/markets/structures/{}/", self.esi_url, location_id)
+    }
+
+    pub fn auth_url(&self) -> String {
+        format!("{}/v2/oauth/token", self.sso_url)
+    }
+
+    pub fn jwks_url(&self) -> String {
+        format!("{}/oauth/jwks", self.sso_url)
+    }
+
+    // SSO has issued tokens with both the bare host and the full URL as iss
+    pub fn sso_issuers(&self) -> Vec<String> {
+        let host: &str = self.sso_url
+            .split_once("://")
+            .map_or(self.sso_url.as_str(), |(_, host)| host);
+        vec![host.to_string(), self.sso_url.clone()]
+    }
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints::new(
+            "https://esi.evetech.net/latest",
+            "https://login.eveonline.com",
+            "tranquility",
+        )
+    }
+}
+
+impl Default for ErrorLimit {
+    fn default() -> Self {
+        ErrorLimit {
+            max_concurrent_requests: 20,
+            slow_below: 50,
+            pause_below: 10,
+        }
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 3,
+            base_delay: 500,
+            max_delay: 10000,
+        }
+    }
+}
+
+impl Default for HistoryRetention {
+    fn default() -> Self {
+        HistoryRetention {
+            raw: 2 * 24 * 60 * 60,
+            resolution: 60 * 60,
+            retention: 90 * 24 * 60 * 60,
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use crate::proto::{
+    MarketOrderDetailed,
+    MarketOrdersDetailedRep,
+    OrderChange,
+    OrderChangesRep,
+};
+
+use std::collections::HashMap;
+
+// Compares two consecutive snapshots of a book by order id. Either may be
+// missing, as a book with no orders is not cached.
+pub fn order_changes(
+    previous: Option<&MarketOrdersDetailedRep>,
+    current: Option<&MarketOrdersDetailedRep>,
+) -> OrderChangesRep {
+    let previous: HashMap<i64, &MarketOrderDetailed> = previous
+        .into_iter()
+        .flat_map(|rep| rep.market_orders.iter())
+        .map(|order| (order.order_id, order))
+        .collect();
+    let current: HashMap<i64, &MarketOrderDetailed> = current
+        .into_iter()
+        .flat_map(|rep| rep.market_orders.iter())
+        .map(|order| (order.order_id, order))
+        .collect();
+
+    let mut rep: OrderChangesRep = OrderChangesRep::default();
+    for (order_id, order) in current.iter() {
+        let before: &MarketOrderDetailed = match previous.get(order_id) {
+            Some(before) => before,
+            None => {
+                rep.new_orders.push((*order).clone());
+                continue;
+            },
+        };
+        if order.price != before.price {
+            rep.price_changes.push(order_change(order, before));
+        }
+        if order.volume_remain < before.volume_remain {
+            rep.traded_volume +=
+                (before.volume_remain - order.volume_remain) as i64;
+            rep.volume_changes.push(order_change(order, before));
+        }
+    }
+    for (order_id, order) in previous.iter() {
+        if !current.contains_key(order_id) {
+            rep.traded_volume += order.volume_remain as i64;
+            rep.removed_orders.push((*order).clone());
+        }
+    }
+
+    // Keep the reply stable regardless of hash order
+    rep.new_orders.sort_by_key(|o| o.order_id);
+    rep.removed_orders.sort_by_key(|o| o.order_id);
+    rep.price_changes.sort_by_key(|c| c.order.as_ref().map(|o| o.order_id));
+    rep.volume_changes.sort_by_key(|c| c.order.as_ref().map(|o| o.order_id));
+    rep
+}
+
+fn order_change(
+    order: &MarketOrderDetailed,
+    before: &MarketOrderDetailed,
+) -> OrderChange {
+    OrderChange {
+        order: Some(order.clone()),
+        previous_price: before.price,
+        previous_volume_remain: before.volume_remain,
+    }
+}
@@ -1,112 +1,624 @@
+#![allow(clippy::redundant_field_names, clippy::redundant_closure)]
+
 use crate::{
-    {LocationId, RegionId, MarketName, RefreshToken},
-    config::{Markets, MinCacheDuration},
+    {LocationId, RegionId, MarketName, RefreshToken, TypeId},
+    config::{
+        BuyRange,
+        Endpoints,
+        ErrorLimit,
+        HistoryRetention,
+        Markets,
+        MinCacheDuration,
+        Prefetch,
+        Retry,
+    },
     esi_client::Client,
+    history::HistoryStore,
+    token_store::FileTokenStore,
     service::Service,
     error::Error,
 };
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     net::SocketAddr,
     env::var,
+    path::Path,
+    str::FromStr,
 };
 
-use serde::Deserialize;
+use serde::{Deserialize, de::DeserializeOwned};
+use reqwest::{Url, header::HeaderValue};
+use jsonwebtoken::jwk::JwkSet;
 use either::Either;
-use serde_json;
+
+// Seconds an expired cache entry may still be served while it refreshes
+const DEFAULT_MAX_STALENESS: u64 = 600;
+// Upper bound in seconds of the random delay added to each prefetch
+const DEFAULT_PREFETCH_JITTER: u64 = 10;
+// Seconds before expiry at which SSO access tokens are renewed
+const DEFAULT_TOKEN_REFRESH_MARGIN: u64 = 60;
+// File rotated refresh tokens are written to
+const DEFAULT_TOKEN_STORE_PATH: &str = "refresh_tokens.json";
+
+// Environment variable naming the config file when no flag is given
+pub const CONFIG_PATH_VAR: &str = "WM_CONFIG_PATH";
 
 pub fn service_from_env() -> Result<Service, Error> {
-    EnvData::from_env_var()?
-        .into_service()
+    service_from_config(None)
 }
 
-#[derive(Deserialize, Debug, Clone)]
-struct EnvData {
-    service_address: String,
-    user_agent: String,
-    client_id: String,
-    client_secret: String,
-    client_timeout: Option<String>,
-    station_mo_timeout: String,
-    structure_mo_timeout: String,
-    adjusted_price_timeout: String,
-    system_index_timeout: String,
-    station_markets: String,
-    structure_markets: String,
+// Reads the settings from the TOML or YAML file at path, if any, lets
+// environment variables override individual keys, and validates the result
+// before anything is started.
+pub fn service_from_config(path: Option<&str>) -> Result<Service, Error> {
+    let mut settings: Settings = match path {
+        Some(path) => Settings::from_file(path)?,
+        None => Settings::default(),
+    };
+    settings.override_from_env()?;
+    settings.into_service()
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Settings {
+    service_address: Option<SocketAddr>,
+    strict: Option<bool>,
+    max_staleness: Option<u64>,
+    jump_graph_path: Option<String>,
+    bulk_regions: Option<HashSet<RegionId>>,
+    esi: EsiSettings,
+    cache: CacheSettings,
+    prefetch: PrefetchSettings,
+    history: HistorySettings,
+    station_markets: Option<HashMap<MarketName, StationMarket>>,
+    structure_markets: Option<HashMap<MarketName, StructureMarket>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+struct EsiSettings {
+    user_agent: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    timeout: Option<u64>,
+    token_refresh_margin: Option<u64>,
+    token_store_path: Option<String>,
+    sso_jwks_path: Option<String>,
+    url: Option<String>,
+    sso_url: Option<String>,
+    datasource: Option<String>,
+    max_concurrent_requests: Option<usize>,
+    error_limit_slow: Option<u32>,
+    error_limit_pause: Option<u32>,
+    retry_attempts: Option<usize>,
+    retry_base_delay_ms: Option<u64>,
+    retry_max_delay_ms: Option<u64>,
+}
+
+// Seconds each cache is kept at least, whatever ESI's expiry
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+struct CacheSettings {
+    station_market_orders: Option<u64>,
+    structure_market_orders: Option<u64>,
+    adjusted_price: Option<u64>,
+    system_index: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+struct PrefetchSettings {
+    enabled: Option<bool>,
+    jitter: Option<u64>,
+    watchlist: Option<Vec<PrefetchItem>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+struct HistorySettings {
+    path: Option<String>,
+    raw_retention: Option<u64>,
+    resolution: Option<u64>,
+    retention: Option<u64>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 struct StationMarket {
     location_id: LocationId,
     region_id: RegionId,
+    // Only needed for range-aware buy orders
+    system_id: Option<i32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct PrefetchItem {
+    region_id: RegionId,
+    type_id: TypeId,
 }
 
 #[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 struct StructureMarket {
     location_id: LocationId,
     refresh_token: Option<RefreshToken>,
 }
 
-impl EnvData {
-    fn from_env_var() -> Result<EnvData, Error> {
-        Ok(EnvData {
-            service_address: var("WM_SERVICE_ADDRESS")?,
-            user_agent: var("WM_USER_AGENT")?,
-            client_id: var("WM_CLIENT_ID")?,
-            client_secret: var("WM_CLIENT_SECRET")?,
-            client_timeout: match var("WM_CLIENT_TIMEOUT") {
-                Ok(timeout) => Some(timeout),
-                Err(std::env::VarError::NotPresent) => None,
-                Err(e) => return Err(Error::from(e)),
-            },
-            station_mo_timeout: var("WM_STATION_MARKET_ORDERS_TIMEOUT")?,
-            structure_mo_timeout: var("WM_STRUCTURE_MARKET_ORDERS_TIMEOUT")?,
-            adjusted_price_timeout: var("WM_ADJUSTED_PRICE_TIMEOUT")?,
-            system_index_timeout: var("WM_SYSTEM_INDEX_TIMEOUT")?,
-            station_markets: var("WM_STATION_MARKETS")?,
-            structure_markets: var("WM_STRUCTURE_MARKETS")?,
-        })
+impl Settings {
+    // The format is taken from the extension
+    fn from_file(path: &str) -> Result<Settings, Error> {
+        let contents: String = std::fs::read_to_string(path)
+            .map_err(|e| Error::ConfigFileParseError(path.to_string(), e.to_string()))?;
+        let extension: Option<&str> = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str());
+        let parsed: Result<Settings, String> = match extension {
+            Some("toml") => toml::from_str(&contents)
+                .map_err(|e| e.to_string()),
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&contents)
+                .map_err(|e| e.to_string()),
+            _ => Err("expected a .toml, .yaml or .yml file".to_string()),
+        };
+        parsed.map_err(|e| Error::ConfigFileParseError(path.to_string(), e))
     }
 
+    fn override_from_env(&mut self) -> Result<(), Error> {
+        override_parsed(
+            &mut self.service_address,
+            "service_address",
+            "WM_SERVICE_ADDRESS",
+        )?;
+        override_parsed(&mut self.strict, "strict", "WM_STRICT")?;
+        override_parsed(
+            &mut self.max_staleness,
+            "max_staleness",
+            "WM_MAX_STALENESS",
+        )?;
+        override_parsed(
+            &mut self.jump_graph_path,
+            "jump_graph_path",
+            "WM_JUMP_GRAPH_PATH",
+        )?;
+        override_json(&mut self.bulk_regions, "bulk_regions", "WM_BULK_REGIONS")?;
+        override_json(
+            &mut self.station_markets,
+            "station_markets",
+            "WM_STATION_MARKETS",
+        )?;
+        override_json(
+            &mut self.structure_markets,
+            "structure_markets",
+            "WM_STRUCTURE_MARKETS",
+        )?;
+
+        let esi: &mut EsiSettings = &mut self.esi;
+        override_parsed(&mut esi.user_agent, "esi.user_agent", "WM_USER_AGENT")?;
+        override_parsed(&mut esi.client_id, "esi.client_id", "WM_CLIENT_ID")?;
+        override_parsed(
+            &mut esi.client_secret,
+            "esi.client_secret",
+            "WM_CLIENT_SECRET",
+        )?;
+        override_parsed(&mut esi.timeout, "esi.timeout", "WM_CLIENT_TIMEOUT")?;
+        override_parsed(
+            &mut esi.token_refresh_margin,
+            "esi.token_refresh_margin",
+            "WM_TOKEN_REFRESH_MARGIN",
+        )?;
+        override_parsed(
+            &mut esi.token_store_path,
+            "esi.token_store_path",
+            "WM_TOKEN_STORE_PATH",
+        )?;
+        override_parsed(
+            &mut esi.sso_jwks_path,
+            "esi.sso_jwks_path",
+            "WM_SSO_JWKS_PATH",
+        )?;
+        override_parsed(&mut esi.url, "esi.url", "WM_ESI_URL")?;
+        override_parsed(&mut esi.sso_url, "esi.sso_url", "WM_SSO_URL")?;
+        override_parsed(&mut esi.datasource, "esi.datasource", "WM_ESI_DATASOURCE")?;
+        override_parsed(
+            &mut esi.max_concurrent_requests,
+            "esi.max_concurrent_requests",
+            "WM_ESI_MAX_CONCURRENT_REQUESTS",
+        )?;
+        override_parsed(
+            &mut esi.error_limit_slow,
+            "esi.error_limit_slow",
+            "WM_ESI_ERROR_LIMIT_SLOW",
+        )?;
+        override_parsed(
+            &mut esi.error_limit_pause,
+            "esi.error_limit_pause",
+            "WM_ESI_ERROR_LIMIT_PAUSE",
+        )?;
+        override_parsed(
+            &mut esi.retry_attempts,
+            "esi.retry_attempts",
+            "WM_ESI_RETRY_ATTEMPTS",
+        )?;
+        override_parsed(
+            &mut esi.retry_base_delay_ms,
+            "esi.retry_base_delay_ms",
+            "WM_ESI_RETRY_BASE_DELAY_MS",
+        )?;
+        override_parsed(
+            &mut esi.retry_max_delay_ms,
+            "esi.retry_max_delay_ms",
+            "WM_ESI_RETRY_MAX_DELAY_MS",
+        )?;
+
+        let cache: &mut CacheSettings = &mut self.cache;
+        override_parsed(
+            &mut cache.station_market_orders,
+            "cache.station_market_orders",
+            "WM_STATION_MARKET_ORDERS_TIMEOUT",
+        )?;
+        override_parsed(
+            &mut cache.structure_market_orders,
+            "cache.structure_market_orders",
+            "WM_STRUCTURE_MARKET_ORDERS_TIMEOUT",
+        )?;
+        override_parsed(
+            &mut cache.adjusted_price,
+            "cache.adjusted_price",
+            "WM_ADJUSTED_PRICE_TIMEOUT",
+        )?;
+        override_parsed(
+            &mut cache.system_index,
+            "cache.system_index",
+            "WM_SYSTEM_INDEX_TIMEOUT",
+        )?;
+
+        let prefetch: &mut PrefetchSettings = &mut self.prefetch;
+        override_parsed(&mut prefetch.enabled, "prefetch.enabled", "WM_PREFETCH")?;
+        override_parsed(
+            &mut prefetch.jitter,
+            "prefetch.jitter",
+            "WM_PREFETCH_JITTER",
+        )?;
+        override_json(
+            &mut prefetch.watchlist,
+            "prefetch.watchlist",
+            "WM_PREFETCH_WATCHLIST",
+        )?;
+
+        let history: &mut HistorySettings = &mut self.history;
+        override_parsed(&mut history.path, "history.path", "WM_HISTORY_PATH")?;
+        override_parsed(
+            &mut history.raw_retention,
+            "history.raw_retention",
+            "WM_HISTORY_RAW_RETENTION",
+        )?;
+        override_parsed(
+            &mut history.resolution,
+            "history.resolution",
+            "WM_HISTORY_RESOLUTION",
+        )?;
+        override_parsed(
+            &mut history.retention,
+            "history.retention",
+            "WM_HISTORY_RETENTION",
+        )?;
+        Ok(())
+    }
+
+    // Checks every setting before anything is opened or started, so a bad
+    // one is reported by name rather than failing later
     fn into_service(self) -> Result<Service, Error> {
-        let service_address: SocketAddr = self.service_address.parse()?;
+        let service_address: SocketAddr = required(
+            self.service_address,
+            "service_address",
+            "WM_SERVICE_ADDRESS",
+        )?;
 
         let min_cache_duration: MinCacheDuration = MinCacheDuration {
-            station_market_orders: self.station_mo_timeout.parse()?,
-            structure_market_orders: self.structure_mo_timeout.parse()?,
-            adjusted_price: self.adjusted_price_timeout.parse()?,
-            system_index: self.system_index_timeout.parse()?,
+            station_market_orders: required(
+                self.cache.station_market_orders,
+                "cache.station_market_orders",
+                "WM_STATION_MARKET_ORDERS_TIMEOUT",
+            )?,
+            structure_market_orders: required(
+                self.cache.structure_market_orders,
+                "cache.structure_market_orders",
+                "WM_STRUCTURE_MARKET_ORDERS_TIMEOUT",
+            )?,
+            adjusted_price: required(
+                self.cache.adjusted_price,
+                "cache.adjusted_price",
+                "WM_ADJUSTED_PRICE_TIMEOUT",
+            )?,
+            system_index: required(
+                self.cache.system_index,
+                "cache.system_index",
+                "WM_SYSTEM_INDEX_TIMEOUT",
+            )?,
         };
 
+        let user_agent: String = required(
+            self.esi.user_agent,
+            "esi.user_agent",
+            "WM_USER_AGENT",
+        )?;
+        if let Err(e) = HeaderValue::from_str(&user_agent) {
+            return Err(Error::InvalidSetting(
+                "esi.user_agent (WM_USER_AGENT)".to_string(),
+                e.to_string(),
+            ));
+        }
+        let client_id: String = required(
+            self.esi.client_id,
+            "esi.client_id",
+            "WM_CLIENT_ID",
+        )?;
+        let client_secret: String = required(
+            self.esi.client_secret,
+            "esi.client_secret",
+            "WM_CLIENT_SECRET",
+        )?;
+
         let station_markets: HashMap<MarketName, StationMarket> =
-            serde_json::from_str(&self.station_markets)
-                .map_err(|e| Error::EnvJsonParseError(e))?;
+            self.station_markets.unwrap_or_default();
         let structure_markets: HashMap<MarketName, StructureMarket> =
-            serde_json::from_str(&self.structure_markets)
-                .map_err(|e| Error::EnvJsonParseError(e))?;
+            self.structure_markets.unwrap_or_default();
+        if station_markets.is_empty() && structure_markets.is_empty() {
+            return Err(Error::InvalidSetting(
+                "station_markets".to_string(),
+                "no station or structure markets are configured".to_string(),
+            ));
+        }
         let mut markets: Markets = Markets::with_capacity(
             station_markets.len() + structure_markets.len()
         );
+        let mut station_systems: HashMap<LocationId, i32> = HashMap::new();
         for (k, v) in station_markets {
+            if let Some(system_id) = v.system_id {
+                station_systems.insert(v.location_id, system_id);
+            }
             markets.insert(k, (v.location_id, Either::Left(v.region_id)));
         }
         for (k, v) in structure_markets {
+            if markets.get(&k).is_some() {
+                return Err(Error::InvalidSetting(
+                    format!("structure_markets.{}", k),
+                    "already configured as a station market".to_string(),
+                ));
+            }
             markets.insert(k, (v.location_id, Either::Right(v.refresh_token)));
         }
-        
+
+        let default_error_limit: ErrorLimit = ErrorLimit::default();
+        let error_limit: ErrorLimit = ErrorLimit {
+            max_concurrent_requests: self.esi.max_concurrent_requests
+                .unwrap_or(default_error_limit.max_concurrent_requests),
+            slow_below: self.esi.error_limit_slow
+                .unwrap_or(default_error_limit.slow_below),
+            pause_below: self.esi.error_limit_pause
+                .unwrap_or(default_error_limit.pause_below),
+        };
+        if error_limit.max_concurrent_requests == 0 {
+            return Err(Error::InvalidSetting(
+                "esi.max_concurrent_requests".to_string(),
+                "must be at least 1".to_string(),
+            ));
+        }
+        if error_limit.pause_below > error_limit.slow_below {
+            return Err(Error::InvalidSetting(
+                "esi.error_limit_pause".to_string(),
+                "must not exceed esi.error_limit_slow".to_string(),
+            ));
+        }
+
+        let default_retry: Retry = Retry::default();
+        let retry: Retry = Retry {
+            attempts: self.esi.retry_attempts
+                .unwrap_or(default_retry.attempts),
+            base_delay: self.esi.retry_base_delay_ms
+                .unwrap_or(default_retry.base_delay),
+            max_delay: self.esi.retry_max_delay_ms
+                .unwrap_or(default_retry.max_delay),
+        };
+        if retry.base_delay > retry.max_delay {
+            return Err(Error::InvalidSetting(
+                "esi.retry_base_delay_ms".to_string(),
+                "must not exceed esi.retry_max_delay_ms".to_string(),
+            ));
+        }
+
+        let default_retention: HistoryRetention = HistoryRetention::default();
+        let retention: HistoryRetention = HistoryRetention {
+            raw: self.history.raw_retention
+                .unwrap_or(default_retention.raw),
+            resolution: self.history.resolution
+                .unwrap_or(default_retention.resolution),
+            retention: self.history.retention
+                .unwrap_or(default_retention.retention),
+        };
+        if retention.resolution == 0 {
+            return Err(Error::InvalidSetting(
+                "history.resolution".to_string(),
+                "must be at least 1".to_string(),
+            ));
+        }
+
+        let jwks: Option<JwkSet> = match self.esi.sso_jwks_path {
+            Some(path) => Some(read_json_file(
+                &path,
+                "esi.sso_jwks_path",
+                "WM_SSO_JWKS_PATH",
+            )?),
+            None => None,
+        };
+
+        let buy_range: Option<BuyRange> = match self.jump_graph_path {
+            Some(path) => Some(BuyRange {
+                jumps: read_json_file(
+                    &path,
+                    "jump_graph_path",
+                    "WM_JUMP_GRAPH_PATH",
+                )?,
+                station_systems: station_systems,
+            }),
+            None => None,
+        };
+
+        let history: Option<HistoryStore> = match self.history.path {
+            Some(path) => Some(
+                HistoryStore::open(&path, retention)
+                    .map_err(|e| Error::InvalidSetting(
+                        "history.path (WM_HISTORY_PATH)".to_string(),
+                        format!("{}: {}", path, e),
+                    ))?
+            ),
+            None => None,
+        };
+
+        let default_endpoints: Endpoints = Endpoints::default();
+        if let Some(url) = &self.esi.url {
+            check_url(url, "esi.url", "WM_ESI_URL")?;
+        }
+        if let Some(url) = &self.esi.sso_url {
+            check_url(url, "esi.sso_url", "WM_SSO_URL")?;
+        }
+        let endpoints: Endpoints = Endpoints::new(
+            self.esi.url
+                .as_deref()
+                .unwrap_or(default_endpoints.esi_url()),
+            self.esi.sso_url
+                .as_deref()
+                .unwrap_or(default_endpoints.sso_url()),
+            self.esi.datasource
+                .as_deref()
+                .unwrap_or(default_endpoints.datasource()),
+        );
+
         let refresh_tokens: Vec<&str> = markets.refresh_tokens();
         let client: Client = Client::new(
-            &self.user_agent,
-            &self.client_id,
-            &self.client_secret,
+            &user_agent,
+            &client_id,
+            &client_secret,
             &refresh_tokens,
-            match self.client_timeout {
-                Some(s) => Some(std::time::Duration::from_secs(s.parse()?)),
-                None => None,
-            },
+            self.esi.timeout.map(|s| std::time::Duration::from_secs(s)),
+            self.esi.token_refresh_margin
+                .unwrap_or(DEFAULT_TOKEN_REFRESH_MARGIN),
+            Box::new(FileTokenStore::new(
+                self.esi.token_store_path
+                    .as_deref()
+                    .unwrap_or(DEFAULT_TOKEN_STORE_PATH),
+            )),
+            jwks,
+            endpoints,
+            error_limit,
+            retry,
         );
 
-        Ok(Service::new(client, markets, min_cache_duration, service_address))
+        let prefetch: Prefetch = Prefetch {
+            enabled: self.prefetch.enabled.unwrap_or(true),
+            jitter: self.prefetch.jitter.unwrap_or(DEFAULT_PREFETCH_JITTER),
+            watchlist: self.prefetch.watchlist
+                .unwrap_or_default()
+                .into_iter()
+                .map(|item| (item.region_id, item.type_id))
+                .collect(),
+        };
+
+        Ok(Service::new(
+            client,
+            markets,
+            min_cache_duration,
+            self.max_staleness.unwrap_or(DEFAULT_MAX_STALENESS),
+            prefetch,
+            buy_range,
+            self.bulk_regions.unwrap_or_default(),
+            history,
+            self.strict.unwrap_or(true),
+            service_address,
+        ))
     }
 }
+
+// Replaces the setting with the environment variable, if it is set
+fn override_parsed<T>(
+    setting: &mut Option<T>,
+    key: &str,
+    name: &str,
+) -> Result<(), Error>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if let Some(value) = read_var(name)? {
+        let parsed: T = value
+            .parse()
+            .map_err(|e: T::Err| Error::InvalidSetting(
+                format!("{} ({})", key, name),
+                e.to_string(),
+            ))?;
+        *setting = Some(parsed);
+    }
+    Ok(())
+}
+
+// Replaces the setting with the JSON in the environment variable, if it is
+// set
+fn override_json<T: DeserializeOwned>(
+    setting: &mut Option<T>,
+    key: &str,
+    name: &str,
+) -> Result<(), Error> {
+    if let Some(value) = read_var(name)? {
+        let parsed: T = serde_json::from_str(&value)
+            .map_err(|e| Error::InvalidSetting(
+                format!("{} ({})", key, name),
+                e.to_string(),
+            ))?;
+        *setting = Some(parsed);
+    }
+    Ok(())
+}
+
+// Parses the JSON file the setting points at, naming the setting and the
+// file if it can't be read or parsed
+fn read_json_file<T: DeserializeOwned>(
+    path: &str,
+    key: &str,
+    name: &str,
+) -> Result<T, Error> {
+    let invalid = |e: String| Error::InvalidSetting(
+        format!("{} ({})", key, name),
+        format!("{}: {}", path, e),
+    );
+    let contents: Vec<u8> = std::fs::read(path)
+        .map_err(|e| invalid(e.to_string()))?;
+    serde_json::from_slice(&contents)
+        .map_err(|e| invalid(e.to_string()))
+}
+
+// Checks that the setting is an absolute http or https URL
+fn check_url(url: &str, key: &str, name: &str) -> Result<(), Error> {
+    let invalid = |e: String| Error::InvalidSetting(
+        format!("{} ({})", key, name),
+        format!("{}: {}", url, e),
+    );
+    let parsed: Url = Url::parse(url).map_err(|e| invalid(e.to_string()))?;
+    match parsed.scheme() {
+        "http" | "https" => Ok(()),
+        _ => Err(invalid("must be an http or https URL".to_string())),
+    }
+}
+
+fn read_var(name: &str) -> Result<Option<String>, Error> {
+    match var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+fn required<T>(setting: Option<T>, key: &str, name: &str) -> Result<T, Error> {
+    setting.ok_or_else(|| Error::MissingSetting(format!("{} ({})", key, name)))
+}
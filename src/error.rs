@@ -1,29 +1,244 @@
-use serde_json;
-use tonic;
+use crate::{
+    {MarketName, RegionId, TypeId},
+    esi_client,
+};
+
+use tonic::{Code, Status, metadata::MetadataValue};
+
+// Longest ESI body forwarded to clients in the 'esi-body' metadata entry
+const MAX_METADATA_BODY_LEN: usize = 1024;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     EnvSocketParseError(std::net::AddrParseError),
     EnvIntParseError(std::num::ParseIntError),
+    EnvBoolParseError(std::str::ParseBoolError),
     EnvJsonParseError(serde_json::Error),
     EnvReadError(std::env::VarError),
+    EnvFileReadError(std::io::Error),
+    ConfigFileParseError(String, String),
+    InvalidSetting(String, String),
+    MissingSetting(String),
     ServiceServeError(tonic::transport::Error),
+    EsiClientError(esi_client::Error),
+    MarketNotFound(MarketName),
+    MarketHasNoRegion(MarketName),
+    TypeIdNotFound(TypeId),
+    InvalidTypeId(TypeId),
+    RegionNotFound(RegionId),
+    SystemIdNotFound(i32),
+    HistoryStoreError(rusqlite::Error),
+    HistoryDisabled,
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::EnvSocketParseError(e) => write!(
+                f,
+                "invalid socket address: {}",
+                e,
+            ),
+            Error::EnvIntParseError(e) => write!(
+                f,
+                "invalid integer: {}",
+                e,
+            ),
+            Error::EnvBoolParseError(e) => write!(
+                f,
+                "invalid boolean: {}",
+                e,
+            ),
+            Error::EnvJsonParseError(e) => write!(
+                f,
+                "invalid json: {}",
+                e,
+            ),
+            Error::EnvReadError(e) => write!(
+                f,
+                "failed to read environment variable: {}",
+                e,
+            ),
+            Error::EnvFileReadError(e) => write!(
+                f,
+                "failed to read file: {}",
+                e,
+            ),
+            Error::ConfigFileParseError(path, e) => write!(
+                f,
+                "invalid config file '{}': {}",
+                path,
+                e,
+            ),
+            Error::InvalidSetting(key, e) => write!(
+                f,
+                "invalid setting {}: {}",
+                key,
+                e,
+            ),
+            Error::MissingSetting(key) => write!(
+                f,
+                "missing setting {}",
+                key,
+            ),
+            Error::ServiceServeError(e) => write!(
+                f,
+                "failed to serve: {}",
+                e,
+            ),
+            Error::EsiClientError(e) => e.fmt(f),
+            Error::MarketNotFound(market) => write!(
+                f,
+                "market '{}' not found",
+                market,
+            ),
+            Error::MarketHasNoRegion(market) => write!(
+                f,
+                "market '{}' is a structure and has no region",
+                market,
+            ),
+            Error::TypeIdNotFound(type_id) => write!(
+                f,
+                "type id {} not found",
+                type_id,
+            ),
+            Error::InvalidTypeId(type_id) => write!(
+                f,
+                "type id {} is not a valid type id",
+                type_id,
+            ),
+            Error::RegionNotFound(region_id) => write!(
+                f,
+                "region {} has no configured station market",
+                region_id,
+            ),
+            Error::SystemIdNotFound(system_id) => write!(
+                f,
+                "system id {} not found",
+                system_id,
+            ),
+            Error::HistoryStoreError(e) => write!(
+                f,
+                "history store failed: {}",
+                e,
+            ),
+            Error::HistoryDisabled => write!(
+                f,
+                "no history store is configured",
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl From<std::env::VarError> for Error {
     fn from(err: std::env::VarError) -> Self {
         Error::EnvReadError(err)
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::EnvFileReadError(err)
+    }
+}
+
 impl From<std::num::ParseIntError> for Error {
     fn from(err: std::num::ParseIntError) -> Self {
         Error::EnvIntParseError(err)
     }
 }
 
+impl From<std::str::ParseBoolError> for Error {
+    fn from(err: std::str::ParseBoolError) -> Self {
+        Error::EnvBoolParseError(err)
+    }
+}
+
 impl From<std::net::AddrParseError> for Error {
     fn from(err: std::net::AddrParseError) -> Self {
         Error::EnvSocketParseError(err)
     }
 }
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::HistoryStoreError(err)
+    }
+}
+
+impl From<esi_client::Error> for Error {
+    fn from(err: esi_client::Error) -> Self {
+        Error::EsiClientError(err)
+    }
+}
+
+impl From<Error> for Status {
+    fn from(err: Error) -> Self {
+        let code: Code = match &err {
+            Error::EsiClientError(e) => esi_code(e),
+            Error::MarketNotFound(_) => Code::NotFound,
+            Error::MarketHasNoRegion(_) => Code::InvalidArgument,
+            Error::TypeIdNotFound(_) => Code::NotFound,
+            Error::InvalidTypeId(_) => Code::InvalidArgument,
+            Error::RegionNotFound(_) => Code::NotFound,
+            Error::SystemIdNotFound(_) => Code::NotFound,
+            Error::HistoryDisabled => Code::FailedPrecondition,
+            _ => Code::Internal,
+        };
+        let mut status: Status = Status::new(code, err.to_string());
+
+        // Forward ESI's own status and body so callers can see what it said
+        if let Error::EsiClientError(
+            esi_client::Error::EsiStatusCode(esi_status, body)
+            | esi_client::Error::AuthenticationStatusCode(esi_status, body)
+        ) = &err {
+            let metadata = status.metadata_mut();
+            metadata.insert(
+                "esi-status",
+                MetadataValue::from(esi_status.as_u16()),
+            );
+            let body: String = body
+                .chars()
+                .filter(|c| c.is_ascii() && !c.is_ascii_control())
+                .take(MAX_METADATA_BODY_LEN)
+                .collect();
+            if let Ok(body) = MetadataValue::try_from(body) {
+                metadata.insert("esi-body", body);
+            }
+        }
+
+        status
+    }
+}
+
+fn esi_code(err: &esi_client::Error) -> Code {
+    match err {
+        esi_client::Error::AuthenticationStatusCode(_, _) => Code::Unauthenticated,
+        esi_client::Error::AuthenticationRequestError(_) => Code::Unauthenticated,
+        esi_client::Error::InvalidAccessToken(_) => Code::Unauthenticated,
+        esi_client::Error::InvalidSubject(_) => Code::Unauthenticated,
+        esi_client::Error::UnknownSigningKey => Code::Unauthenticated,
+        esi_client::Error::MissingScope(_) => Code::PermissionDenied,
+        esi_client::Error::JwksRequestError(_) => Code::Unavailable,
+        esi_client::Error::EsiStatusCode(status, _) => match status.as_u16() {
+            401 => Code::Unauthenticated,
+            403 => Code::PermissionDenied,
+            404 => Code::NotFound,
+            420 | 429 => Code::ResourceExhausted,
+            500..=599 => Code::Unavailable,
+            _ => Code::Internal,
+        },
+        esi_client::Error::ReqwestClientError(e) if e.is_timeout() => {
+            Code::DeadlineExceeded
+        },
+        esi_client::Error::ReqwestClientError(_) => Code::Unavailable,
+        esi_client::Error::PageExpiryMismatch => Code::Unavailable,
+        esi_client::Error::JsonParseError(_) => Code::Internal,
+        esi_client::Error::MissingHeader(_) => Code::Internal,
+        esi_client::Error::InvalidHeader(_) => Code::Internal,
+        esi_client::Error::RejectedAccessToken(e) => esi_code(e),
+    }
+}
@@ -1,60 +1,164 @@
+#![allow(clippy::redundant_field_names, clippy::redundant_closure)]
+
 use crate::{
     {LocationId, RegionId, TypeId},
+    config::{Endpoints, ErrorLimit, Retry},
+    governor::{Governor, GovernorState},
     json::*,
+    token_store::TokenStore,
+    jwt::{TokenInfo, Verifier},
     time,
 };
 
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    sync::{Arc, atomic::{AtomicU64, Ordering}},
+    time::Duration,
 };
 
 use futures::stream::{TryStreamExt, futures_unordered::FuturesUnordered};
 use reqwest::{self, header::{self, HeaderValue, HeaderMap}};
+use serde::de::DeserializeOwned;
+use tokio::sync::{Mutex, MutexGuard};
+use jsonwebtoken::jwk::JwkSet;
 use chrono::DateTime;
-use base64;
+use bytes::Bytes;
 
-const ADJUSTED_PRICE_URL: &str = "https://esi.evetech.net/latest/markets/prices/";
-const SYSTEM_INDEX_URL: &str = "https://esi.evetech.net/latest/industry/systems/";
-const AUTH_URL: &str = "https://login.eveonline.com/v2/oauth/token";
-const HOST_URL: &str = "login.eveonline.com";
 const ORDERS_PER_PAGE: usize = 1000;
-
-fn station_order_url(region_id: &RegionId) -> String {
-    format!(
-        "https://esi.evetech.net/latest/markets/{}/orders/",
-        region_id,
-    )
-}
-
-fn structure_order_url(location_id: &LocationId) -> String {
-    format!(
-        "https://esi.evetech.net/latest/markets/structures/{}/",
-        location_id,
-    )
-}
+const MAX_PAGE_WALK_ATTEMPTS: usize = 3;
+// Seconds a refresh token whose access tokens fail verification is left
+// alone before SSO is asked again
+const TOKEN_REJECTION_BACKOFF: u64 = 60;
+// Seconds the same is done when the signing keys could not be fetched, which
+// is more likely to pass quickly
+const JWKS_FAILURE_BACKOFF: u64 = 10;
+// Total size of the remembered response bodies before the least recently
+// used ones are forgotten
+const MAX_VALIDATOR_BYTES: usize = 256 * 1024 * 1024;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
-    AuthenticationStatusCode(reqwest::StatusCode),
-    JsonParseError(reqwest::Error),
+    AuthenticationStatusCode(reqwest::StatusCode, String),
+    AuthenticationRequestError(reqwest::Error),
+    EsiStatusCode(reqwest::StatusCode, String),
+    JsonParseError(serde_json::Error),
     ReqwestClientError(reqwest::Error),
+    InvalidAccessToken(jsonwebtoken::errors::Error),
+    InvalidSubject(String),
+    MissingScope(&'static str),
+    UnknownSigningKey,
+    JwksRequestError(reqwest::Error),
+    MissingHeader(&'static str),
+    InvalidHeader(&'static str),
+    PageExpiryMismatch,
+    // A verification failure remembered from an earlier refresh
+    RejectedAccessToken(Arc<Error>),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::AuthenticationStatusCode(status, body) => write!(
+                f,
+                "token refresh failed with status {}: {}",
+                status,
+                body,
+            ),
+            Error::AuthenticationRequestError(e) => write!(
+                f,
+                "token refresh request failed: {}",
+                e,
+            ),
+            Error::EsiStatusCode(status, body) => write!(
+                f,
+                "ESI responded with status {}: {}",
+                status,
+                body,
+            ),
+            Error::JsonParseError(e) => write!(
+                f,
+                "failed to parse ESI response: {}",
+                e,
+            ),
+            Error::ReqwestClientError(e) => write!(
+                f,
+                "ESI request failed: {}",
+                e,
+            ),
+            Error::InvalidAccessToken(e) => write!(
+                f,
+                "access token failed verification: {}",
+                e,
+            ),
+            Error::InvalidSubject(sub) => write!(
+                f,
+                "access token has an invalid subject '{}'",
+                sub,
+            ),
+            Error::MissingScope(scope) => write!(
+                f,
+                "access token was not granted the '{}' scope",
+                scope,
+            ),
+            Error::UnknownSigningKey => write!(
+                f,
+                "access token is signed with an unknown key",
+            ),
+            Error::JwksRequestError(e) => write!(
+                f,
+                "failed to fetch SSO signing keys: {}",
+                e,
+            ),
+            Error::MissingHeader(name) => write!(
+                f,
+                "ESI response is missing the '{}' header",
+                name,
+            ),
+            Error::InvalidHeader(name) => write!(
+                f,
+                "ESI response has an invalid '{}' header",
+                name,
+            ),
+            Error::PageExpiryMismatch => write!(
+                f,
+                "ESI pages kept changing expiry while being fetched",
+            ),
+            Error::RejectedAccessToken(e) => write!(f, "{}", e),
+        }
+    }
 }
 
+impl std::error::Error for Error {}
+
 pub struct Client {
     client: reqwest::Client,
-    blocking_client: reqwest::blocking::Client,
     auth_headers: HeaderMap,
     auth_tokens: HashMap<String, Arc<Mutex<AuthToken>>>,
+    token_store: Arc<dyn TokenStore>,
+    verifier: Verifier,
+    validators: std::sync::Mutex<Validators>,
+    bytes_saved: AtomicU64,
+    governor: Governor,
+    retry: Retry,
+    endpoints: Endpoints,
+    refresh_margin: u64,
 }
 
 impl Client {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         user_agent: &str,
         client_id: &str,
         client_secret: &str,
         refresh_tokens: &Vec<&str>,
         timeout: Option<std::time::Duration>,
+        refresh_margin: u64,
+        token_store: Box<dyn TokenStore>,
+        jwks: Option<JwkSet>,
+        endpoints: Endpoints,
+        error_limit: ErrorLimit,
+        retry: Retry,
     ) -> Client {
         let client: reqwest::Client = match timeout {
             Some(t) => reqwest::ClientBuilder::new().timeout(t),
@@ -75,25 +179,6 @@ impl Client {
             .build()
             .unwrap();
 
-        let blocking_client: reqwest::blocking::Client = match timeout {
-            Some(t) => reqwest::blocking::ClientBuilder::new().timeout(t),
-            None => reqwest::blocking::ClientBuilder::new(),
-        }
-            .default_headers({
-                let mut header_map = HeaderMap::new();
-                header_map.insert(
-                    header::USER_AGENT,
-                    HeaderValue::from_str(user_agent).unwrap(),
-                );
-                header_map.insert(
-                    header::ACCEPT,
-                    HeaderValue::from_static("application/json"),
-                );
-                header_map
-            })
-            .build()
-            .unwrap();
-
         let mut auth_headers: HeaderMap = HeaderMap::new();
         auth_headers.insert(
             header::AUTHORIZATION,
@@ -104,25 +189,37 @@ impl Client {
             ))))
                 .unwrap(),
         );
-        auth_headers.insert(
-            header::HOST,
-            HeaderValue::from_static(HOST_URL),
-        );
 
+        let mut rotated_tokens = token_store
+            .load()
+            .unwrap_or_else(|e| {
+                println!("Failed to load stored refresh tokens: {}", e);
+                HashMap::new()
+            });
         let mut auth_tokens: HashMap<String, Arc<Mutex<AuthToken>>> =
             HashMap::new();
         for refresh_token in refresh_tokens {
+            let current: String = rotated_tokens
+                .remove(*refresh_token)
+                .unwrap_or_else(|| refresh_token.to_string());
             auth_tokens.insert(
                 refresh_token.to_string(),
-                Arc::new(Mutex::new(AuthToken::new())),
+                Arc::new(Mutex::new(AuthToken::new(current))),
             );
         }
 
         Client {
             client: client,
-            blocking_client: blocking_client,
             auth_headers: auth_headers,
             auth_tokens: auth_tokens,
+            token_store: Arc::from(token_store),
+            verifier: Verifier::new(client_id, jwks, &endpoints),
+            validators: std::sync::Mutex::new(Validators::default()),
+            bytes_saved: AtomicU64::new(0),
+            governor: Governor::new(error_limit),
+            retry: retry,
+            endpoints: endpoints,
+            refresh_margin: refresh_margin,
         }
     }
 
@@ -137,205 +234,618 @@ impl Client {
         )
     }
 
-    fn try_authenticate(
+    async fn try_authenticate(
         &self,
         refresh_token: Option<&str>,
         query: reqwest::RequestBuilder,
     ) -> Result<reqwest::RequestBuilder, Error> {
-        let refresh_token: &str = match refresh_token {
-            Some(s) => s,
-            None => return Ok(query),
-        };
+        match refresh_token {
+            Some(refresh_token) => {
+                let auth_token = self.authenticated(refresh_token).await?;
+                Ok(self.add_auth_header(&auth_token.access_token, query))
+            },
+            None => Ok(query),
+        }
+    }
+
+    // Returns the character and scopes of the access token for the refresh
+    // token, refreshing it first if needed
+    pub async fn token_info(
+        &self,
+        refresh_token: &str,
+    ) -> Result<TokenInfo, Error> {
+        let auth_token = self.authenticated(refresh_token).await?;
+        Ok(auth_token
+            .info
+            .clone()
+            .expect("authenticated tokens are always verified"))
+    }
 
-        let auth_token_ref = self.auth_tokens[refresh_token].clone();
-        let auth_token = auth_token_ref.lock().unwrap(); // Read Only
-        if !auth_token.expired() {
-            return Ok(self.add_auth_header(&auth_token.access_token, query))
+    // Holding the token's lock for the whole refresh means concurrent
+    // requests with the same refresh token wait for a single SSO round-trip.
+    // The refresh token is the one from the configuration, which keys the
+    // newest rotation SSO has returned for it.
+    async fn authenticated(
+        &self,
+        refresh_token: &str,
+    ) -> Result<MutexGuard<'_, AuthToken>, Error> {
+        let mut auth_token = self.auth_tokens[refresh_token].lock().await;
+        if !auth_token.expires_within(self.refresh_margin) {
+            return Ok(auth_token)
         }
 
         let now: u64 = time::now();
-        let rep: reqwest::blocking::Response = self
-            .blocking_client
-            .post(AUTH_URL)
+        if let Some((until, e)) = &auth_token.rejection {
+            if now < *until {
+                return Err(Error::RejectedAccessToken(e.clone()))
+            }
+        }
+        let rep: reqwest::Response = self
+            .client
+            .post(self.endpoints.auth_url())
             .headers(self.auth_headers.clone())
             .form(&[
                 ("grant_type", "refresh_token"),
-                ("refresh_token", refresh_token),
+                ("refresh_token", &auth_token.refresh_token),
             ])
             .send()
-            .map_err(|e| Error::ReqwestClientError(e))?;
+            .await
+            .map_err(|e| Error::AuthenticationRequestError(e))?;
         if rep.status() != 200 {
-            return Err(Error::AuthenticationStatusCode(rep.status()))
+            return Err(Error::AuthenticationStatusCode(
+                rep.status(),
+                rep.text().await.unwrap_or_default(),
+            ))
         }
 
         let data: AuthenticationResponse = rep.json()
-            .map_err(|e| Error::JsonParseError(e))?;
+            .await
+            .map_err(|e| Error::AuthenticationRequestError(e))?;
+
+        // The previous refresh token may already be revoked, so a rotated
+        // one is kept even if the access token turns out to be unusable
+        if let Some(rotated) = data.refresh_token {
+            if rotated != auth_token.refresh_token {
+                let token_store: Arc<dyn TokenStore> = self.token_store.clone();
+                let (configured, current) = (refresh_token.to_string(), rotated.clone());
+                // The store does file I/O, keep it off the runtime threads
+                let saved = tokio::task::spawn_blocking(move || token_store.save(&configured, &current))
+                    .await;
+                match saved {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => println!("Failed to store rotated refresh token: {}", e),
+                    Err(e) => println!("Failed to store rotated refresh token: {}", e),
+                }
+                auth_token.refresh_token = rotated;
+            }
+        }
+
+        // Verification failures are remembered for a while, so that SSO is
+        // not asked for a new token, and maybe a new rotation, every request
+        let info: TokenInfo = match self.verifier
+            .verify(&self.client, &data.access_token)
+            .await
+        {
+            Ok(info) => info,
+            Err(e) => {
+                let backoff: u64 = match e {
+                    Error::JwksRequestError(_) => JWKS_FAILURE_BACKOFF,
+                    _ => TOKEN_REJECTION_BACKOFF,
+                };
+                let e: Arc<Error> = Arc::new(e);
+                auth_token.rejection = Some((now + backoff, e.clone()));
+                return Err(Error::RejectedAccessToken(e))
+            },
+        };
 
-        let mut auth_token = auth_token; // Mutable
+        auth_token.rejection = None;
         auth_token.access_token = data.access_token;
         auth_token.expiry = now + data.expires_in;
+        auth_token.info = Some(info);
 
-        let auth_token = auth_token; // Read Only
-        Ok(self.add_auth_header(&auth_token.access_token, query))
+        Ok(auth_token)
     }
 
+    // Inner is None if the orders are unchanged since they were last fetched
     pub async fn get_structure_orders(
         &self,
         location_id: &LocationId,
         refresh_token: Option<&str>,
-    ) -> Result<Expirable<Vec<StructureOrder>>, Error> {
-        let page_count: usize = self
+    ) -> Result<Expirable<Option<Vec<StructureOrder>>>, Error> {
+        self.get_pages(
+            &self.endpoints.structure_order_url(location_id),
+            &[],
+            refresh_token,
+        )
+            .await
+    }
+
+    // Inner is None if the orders are unchanged since they were last fetched
+    pub async fn get_station_orders(
+        &self,
+        region_id: &RegionId,
+        order_type: &str,
+        type_id: &TypeId,
+    ) -> Result<Expirable<Option<Vec<StationOrder>>>, Error> {
+        self.get_pages(
+            &self.endpoints.station_order_url(region_id),
+            &[
+                ("order_type", order_type),
+                ("type_id", &type_id.to_string()),
+            ],
+            None,
+        )
+            .await
+    }
+
+    // Every order of every type in the region, for bulk ingestion. Inner is
+    // None if the orders are unchanged since they were last fetched
+    pub async fn get_region_orders(
+        &self,
+        region_id: &RegionId,
+    ) -> Result<Expirable<Option<Vec<StationOrder>>>, Error> {
+        self.get_pages(
+            &self.endpoints.station_order_url(region_id),
+            &[("order_type", "all")],
+            None,
+        )
+            .await
+    }
+
+    // Walks every page of a paginated endpoint, retrying the walk if ESI
+    // rolls over to a new snapshot partway through.
+    async fn get_pages<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        query: &[(&str, &str)],
+        refresh_token: Option<&str>,
+    ) -> Result<Expirable<Option<Vec<T>>>, Error> {
+        let mut attempts: usize = 0;
+        loop {
+            attempts += 1;
+            match self.walk_pages(url, query, refresh_token).await? {
+                Some(pages) => return Ok(pages),
+                None if attempts >= MAX_PAGE_WALK_ATTEMPTS => {
+                    return Err(Error::PageExpiryMismatch)
+                },
+                None => continue,
+            }
+        }
+    }
+
+    // Returns None if any page disagrees with the first HEAD on either its
+    // expiry or the page count, as the pages then belong to different snapshots.
+    async fn walk_pages<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        query: &[(&str, &str)],
+        refresh_token: Option<&str>,
+    ) -> Result<Option<Expirable<Option<Vec<T>>>>, Error> {
+        let head: reqwest::Request = self
             .try_authenticate(
                 refresh_token,
                 self.client
-                    .head(structure_order_url(location_id))
+                    .head(url)
                     .query(&[
-                        ("datasource", "tranquility"),
+                        ("datasource", self.endpoints.datasource()),
                         ("page", "1"),
-                    ]),
-            )?
-            .send()
-            .await
-            .map_err(|e| Error::ReqwestClientError(e))?
-            .headers()
-            .get("x-pages")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .parse()
-            .unwrap();
+                    ])
+                    .query(query),
+            )
+            .await?
+            .build()
+            .map_err(|e| Error::ReqwestClientError(e))?;
+        let head: Received = check_status(self.execute(head).await?)?;
+        let pages: usize = page_count(&head.headers)?;
+        let snapshot_expires_in: u64 = expires_in(&head.headers)?;
 
-        let mut req_futures = FuturesUnordered::new();
-        for i in 1..page_count + 1 {
-            req_futures.push(
-                self.try_authenticate(
-                    refresh_token,
-                    self.client
-                        .get(structure_order_url(location_id))
-                        .query(&[
-                            ("datasource", "tranquility"),
-                            ("page", &format!("{:?}", i)),
-                        ]),
-                )?
-                .send()
-            );
+        let mut page_futures = FuturesUnordered::new();
+        for i in 1..pages + 1 {
+            page_futures.push(self.get_page(url, query, Some(i), refresh_token));
         }
 
-        let mut parse_futures = FuturesUnordered::new();
-        let mut greatest_expires_in: u64 = 0;
-        while let Some(rep) = req_futures
-            .try_next()
-            .await
-            .map_err(|e| Error::ReqwestClientError(e))?
-        {
-            let expires_in = expires_in(&rep);
-            if expires_in > greatest_expires_in {
-                greatest_expires_in = expires_in;
+        let mut bodies: Vec<Page> = Vec::with_capacity(pages);
+        while let Some(page) = page_futures.try_next().await? {
+            if page.expires_in != snapshot_expires_in
+                || matches!(page.pages, Some(p) if p != pages)
+            {
+                return Ok(None);
             }
-            parse_futures.push(rep.json::<Vec<StructureOrder>>());
+            bodies.push(page);
         }
 
-        let mut structure_orders: Vec<StructureOrder> = {
-            Vec::with_capacity(page_count * ORDERS_PER_PAGE)
-        };
-        while let Some(orders) = parse_futures
-            .try_next()
-            .await
-            .map_err(|e| Error::JsonParseError(e))?
-        {
-            for order in orders.into_iter() {
-                structure_orders.push(order);
-            }
+        if bodies.iter().all(|page| !page.modified) {
+            return Ok(Some(Expirable::new(None, snapshot_expires_in)));
+        }
+        let mut items: Vec<T> = Vec::with_capacity(pages * ORDERS_PER_PAGE);
+        for page in bodies.iter() {
+            items.extend(page.parse::<T>()?);
         }
+        self.remember(&bodies);
 
-        Ok(Expirable::new(structure_orders, greatest_expires_in))
+        Ok(Some(Expirable::new(Some(items), snapshot_expires_in)))
     }
 
-    pub async fn get_station_orders(
+    // Fetches a single page, revalidating it with the etag of the last
+    // response for the same URL. A 304 reuses the remembered body.
+    async fn get_page(
         &self,
-        region_id: &RegionId,
-        order_type: &str,
-        type_id: &TypeId,
-    ) -> Result<Expirable<Vec<StationOrder>>, Error> {
-        let rep: reqwest::Response = self.client
-            .get(station_order_url(region_id))
-            .query(&[
-                ("datasource", "tranquility"),
-                ("page", "1"),
-                ("order_type", order_type),
-                ("type_id", &type_id.to_string()),
-            ])
-            .send()
-            .await
+        url: &str,
+        query: &[(&str, &str)],
+        page: Option<usize>,
+        refresh_token: Option<&str>,
+    ) -> Result<Page, Error> {
+        let mut query_builder: reqwest::RequestBuilder = self.client
+            .get(url)
+            .query(&[("datasource", self.endpoints.datasource())]);
+        if let Some(page) = page {
+            query_builder = query_builder.query(&[("page", page.to_string())]);
+        }
+        let mut req: reqwest::Request = self
+            .try_authenticate(refresh_token, query_builder.query(query))
+            .await?
+            .build()
             .map_err(|e| Error::ReqwestClientError(e))?;
-        let expires_in: u64 = expires_in(&rep);
-        rep.json::<Vec<StationOrder>>()
-            .await
-            .map_err(|e| Error::JsonParseError(e))
-            .map(|v| { Expirable::new(v, expires_in) })
+
+        let key: String = req.url().to_string();
+        let etag: Option<HeaderValue> = self.validators
+            .lock()
+            .unwrap()
+            .get(&key)
+            .and_then(|v| HeaderValue::from_str(&v.etag).ok());
+        if let Some(etag) = etag {
+            req.headers_mut().insert(header::IF_NONE_MATCH, etag);
+        }
+
+        let rep: Received = self.execute(req).await?;
+        if rep.status == reqwest::StatusCode::NOT_MODIFIED {
+            let body: Option<Bytes> = self.validators
+                .lock()
+                .unwrap()
+                .get(&key)
+                .map(|v| v.body.clone());
+            if let Some(body) = body {
+                self.bytes_saved.fetch_add(body.len() as u64, Ordering::Relaxed);
+                return Ok(Page {
+                    key: key,
+                    etag: None,
+                    expires_in: expires_in(&rep.headers)?,
+                    pages: optional_page_count(&rep.headers)?,
+                    body: body,
+                    modified: false,
+                });
+            }
+        }
+
+        let rep: Received = check_status(rep)?;
+        let expires_in: u64 = expires_in(&rep.headers)?;
+        let pages: Option<usize> = optional_page_count(&rep.headers)?;
+        let etag: Option<String> = rep.headers
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+
+        Ok(Page {
+            key: key,
+            etag: etag,
+            expires_in: expires_in,
+            pages: pages,
+            body: rep.body,
+            modified: true,
+        })
+    }
+
+    // Sends a request to ESI once the governor allows it, recording the error
+    // budget ESI reports. GETs and HEADs are retried on transient failures,
+    // waiting for as long as ESI asks to if it sends a Retry-After. The body
+    // is read under the same permit, so that the governor also bounds how
+    // many bodies are downloaded at once.
+    async fn execute(
+        &self,
+        mut req: reqwest::Request,
+    ) -> Result<Received, Error> {
+        let idempotent: bool = matches!(
+            *req.method(),
+            reqwest::Method::GET | reqwest::Method::HEAD,
+        );
+        let mut attempt: usize = 1;
+        loop {
+            let retry_req: Option<reqwest::Request> = match idempotent
+                && attempt < self.retry.attempts
+            {
+                true => req.try_clone(),
+                false => None,
+            };
+            let url: String = req.url().to_string();
+
+            let rep: Result<Received, reqwest::Error> = {
+                let _permit = self.governor.acquire().await;
+                match self.client.execute(req).await {
+                    Ok(rep) => {
+                        self.governor.observe(rep.headers());
+                        let (status, headers) = (rep.status(), rep.headers().clone());
+                        rep.bytes().await.map(|body| Received {
+                            status: status,
+                            headers: headers,
+                            body: body,
+                        })
+                    },
+                    Err(e) => Err(e),
+                }
+            };
+
+            let retry_req: reqwest::Request = match retry_req {
+                Some(retry_req) => retry_req,
+                None => return rep.map_err(|e| Error::ReqwestClientError(e)),
+            };
+            let (delay, reason): (Duration, String) = match &rep {
+                Ok(rep) if retryable_status(rep.status) => (
+                    retry_after(&rep.headers)
+                        .map(|delay| delay.min(Duration::from_millis(self.retry.max_delay)))
+                        .unwrap_or_else(|| self.retry.delay(attempt)),
+                    format!("status {}", rep.status),
+                ),
+                Err(e) if retryable_error(e) => (
+                    self.retry.delay(attempt),
+                    e.to_string(),
+                ),
+                _ => return rep.map_err(|e| Error::ReqwestClientError(e)),
+            };
+            println!(
+                "Retrying ESI request {} in {}ms after attempt {} failed: {}",
+                url,
+                delay.as_millis(),
+                attempt,
+                reason,
+            );
+            tokio::time::sleep(delay).await;
+            req = retry_req;
+            attempt += 1;
+        }
+    }
+
+    // Remembers the etags of pages once they are known to make up a whole
+    // snapshot and to parse, so that a 304 on every page means the snapshot
+    // is unchanged.
+    fn remember(&self, pages: &[Page]) {
+        let mut validators = self.validators.lock().unwrap();
+        for page in pages.iter().filter(|page| page.modified) {
+            match &page.etag {
+                Some(etag) => validators.insert(&page.key, etag, &page.body),
+                None => validators.remove(&page.key),
+            };
+        }
     }
 
+    // Inner is None if the prices are unchanged since they were last fetched
     pub async fn get_adjusted_price(
         &self,
-    ) -> Result<Expirable<Vec<AdjustedPrice>>, Error> {
-        let rep: reqwest::Response = self.client
-            .get(ADJUSTED_PRICE_URL)
-            .query(&[("datasource", "tranquility")])
-            .send()
-            .await
-            .map_err(|e| Error::ReqwestClientError(e))?;
-        let expires_in: u64 = expires_in(&rep);
-        rep.json::<Vec<AdjustedPrice>>()
-            .await
-            .map_err(|e| Error::JsonParseError(e))
-            .map(|v| { Expirable::new(v, expires_in) })
+    ) -> Result<Expirable<Option<Vec<AdjustedPrice>>>, Error> {
+        let page: Page = self
+            .get_page(&self.endpoints.adjusted_price_url(), &[], None, None)
+            .await?;
+        let parsed = page.parse_modified()?;
+        self.remember(std::slice::from_ref(&page));
+        Ok(parsed)
     }
 
+    // Inner is None if the indices are unchanged since they were last fetched
     pub async fn get_system_index(
         &self,
-    ) -> Result<Expirable<Vec<SystemIndex>>, Error> {
-        let rep: reqwest::Response = self.client
-            .get(SYSTEM_INDEX_URL)
-            .query(&[("datasource", "tranquility")])
-            .send()
-            .await
-            .map_err(|e| Error::ReqwestClientError(e))?;
-        let expires_in: u64 = expires_in(&rep);
-        rep.json::<Vec<SystemIndex>>()
-            .await
+    ) -> Result<Expirable<Option<Vec<SystemIndex>>>, Error> {
+        let page: Page = self
+            .get_page(&self.endpoints.system_index_url(), &[], None, None)
+            .await?;
+        let parsed = page.parse_modified()?;
+        self.remember(std::slice::from_ref(&page));
+        Ok(parsed)
+    }
+
+    // Parsed even if unchanged since it was last fetched, as the service may
+    // have dropped its copy while the etag is still remembered
+    pub async fn get_market_history(
+        &self,
+        region_id: &RegionId,
+        type_id: &TypeId,
+    ) -> Result<Expirable<Vec<MarketHistory>>, Error> {
+        let page: Page = self
+            .get_page(
+                &self.endpoints.market_history_url(region_id),
+                &[("type_id", &type_id.to_string())],
+                None,
+                None,
+            )
+            .await?;
+        let parsed: Vec<MarketHistory> = page.parse()?;
+        self.remember(std::slice::from_ref(&page));
+        Ok(Expirable::new(parsed, page.expires_in))
+    }
+
+    pub fn governor_state(&self) -> GovernorState {
+        self.governor.state()
+    }
+
+    // Total size of the response bodies ESI did not have to resend
+    pub fn bytes_saved(&self) -> u64 {
+        self.bytes_saved.load(Ordering::Relaxed)
+    }
+}
+
+// The etag and body of the last response for a URL
+struct Validator {
+    etag: String,
+    body: Bytes,
+    used: u64,
+}
+
+// Validators by URL, bounded by the total size of their bodies
+#[derive(Default)]
+struct Validators {
+    entries: HashMap<String, Validator>,
+    bytes: usize,
+    // Incremented on every use to order the entries by recency
+    clock: u64,
+}
+
+impl Validators {
+    fn get(&mut self, key: &str) -> Option<&Validator> {
+        self.clock += 1;
+        let clock: u64 = self.clock;
+        self.entries.get_mut(key).map(|validator| {
+            validator.used = clock;
+            &*validator
+        })
+    }
+
+    fn insert(&mut self, key: &str, etag: &str, body: &Bytes) {
+        self.remove(key);
+        self.clock += 1;
+        self.bytes += body.len();
+        self.entries.insert(key.to_string(), Validator {
+            etag: etag.to_string(),
+            body: body.clone(),
+            used: self.clock,
+        });
+
+        while self.bytes > MAX_VALIDATOR_BYTES {
+            let oldest: String = match self.entries
+                .iter()
+                .min_by_key(|(_, validator)| validator.used)
+            {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(validator) = self.entries.remove(key) {
+            self.bytes -= validator.body.len();
+        }
+    }
+}
+
+struct Page {
+    key: String,
+    etag: Option<String>,
+    expires_in: u64,
+    pages: Option<usize>,
+    body: Bytes,
+    modified: bool,
+}
+
+impl Page {
+    fn parse<T: DeserializeOwned>(&self) -> Result<Vec<T>, Error> {
+        serde_json::from_slice(&self.body)
             .map_err(|e| Error::JsonParseError(e))
-            .map(|v| { Expirable::new(v, expires_in) })
+    }
+
+    fn parse_modified<T: DeserializeOwned>(
+        &self,
+    ) -> Result<Expirable<Option<Vec<T>>>, Error> {
+        match self.modified {
+            true => Ok(Expirable::new(Some(self.parse()?), self.expires_in)),
+            false => Ok(Expirable::new(None, self.expires_in)),
+        }
+    }
+}
+
+// A response from ESI with its body read
+struct Received {
+    status: reqwest::StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+// Turns any non-success status into an error carrying ESI's response body.
+fn check_status(response: Received) -> Result<Received, Error> {
+    match response.status.is_success() {
+        true => Ok(response),
+        false => Err(Error::EsiStatusCode(
+            response.status,
+            String::from_utf8_lossy(&response.body).into_owned(),
+        )),
     }
 }
 
-fn expires_in(response: &reqwest::Response) -> u64 {
-    u64::try_from(DateTime::parse_from_rfc2822(response
-        .headers()
-        .get("expires")
-        .unwrap()
+fn header<'h>(
+    headers: &'h HeaderMap,
+    name: &'static str,
+) -> Result<&'h str, Error> {
+    headers
+        .get(name)
+        .ok_or(Error::MissingHeader(name))?
         .to_str()
-        .unwrap()
-    )
-        .unwrap()
-        .timestamp()
-    )
-        .unwrap()
+        .map_err(|_| Error::InvalidHeader(name))
+}
+
+fn page_count(headers: &HeaderMap) -> Result<usize, Error> {
+    header(headers, "x-pages")?
+        .parse()
+        .map_err(|_| Error::InvalidHeader("x-pages"))
+}
+
+fn retryable_status(status: reqwest::StatusCode) -> bool {
+    matches!(status.as_u16(), 502..=504)
+}
+
+// Includes a body that breaks off, as the body is read with every attempt
+fn retryable_error(err: &reqwest::Error) -> bool {
+    err.is_timeout() || err.is_connect() || err.is_request() || err.is_body()
+}
+
+// Retry-After as either a number of seconds or an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value: &str = headers
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?;
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => DateTime::parse_from_rfc2822(value)
+            .ok()
+            .and_then(|date| u64::try_from(date.timestamp()).ok())
+            .map(|date| Duration::from_secs(date.saturating_sub(time::now()))),
+    }
+}
+
+fn optional_page_count(headers: &HeaderMap) -> Result<Option<usize>, Error> {
+    match headers.contains_key("x-pages") {
+        true => page_count(headers).map(Some),
+        false => Ok(None),
+    }
+}
+
+fn expires_in(headers: &HeaderMap) -> Result<u64, Error> {
+    DateTime::parse_from_rfc2822(header(headers, "expires")?)
+        .ok()
+        .and_then(|expires| u64::try_from(expires.timestamp()).ok())
+        .ok_or(Error::InvalidHeader("expires"))
 }
 
 struct AuthToken {
+    refresh_token: String,
     access_token: String,
     expiry: u64,
+    info: Option<TokenInfo>,
+    // Until when the last verification failure is returned without asking SSO
+    rejection: Option<(u64, Arc<Error>)>,
 }
 
 impl AuthToken {
-    fn new() -> AuthToken {
+    fn new(refresh_token: String) -> AuthToken {
         AuthToken {
+            refresh_token: refresh_token,
             access_token: "".to_string(),
             expiry: 0,
+            info: None,
+            rejection: None,
         }
     }
 
-    fn expired(&self) -> bool {
-        time::now() >= self.expiry
+    // Returns true if the token expires in less than margin seconds
+    fn expires_within(&self, margin: u64) -> bool {
+        time::now() + margin >= self.expiry
     }
 }
@@ -0,0 +1,166 @@
+#![allow(clippy::redundant_field_names)]
+
+use crate::{
+    config::ErrorLimit,
+    time,
+};
+
+use std::{
+    sync::{Mutex, atomic::{AtomicU64, Ordering}},
+    time::Duration,
+};
+
+use reqwest::header::HeaderMap;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+const ERROR_LIMIT_REMAIN_HEADER: &str = "x-esi-error-limit-remain";
+const ERROR_LIMIT_RESET_HEADER: &str = "x-esi-error-limit-reset";
+
+// Shared by every ESI request of a Client, so that together they stay within
+// ESI's error budget and never have too many requests in flight at once.
+pub struct Governor {
+    config: ErrorLimit,
+    permits: Semaphore,
+    budget: Mutex<Budget>,
+    delayed_requests: AtomicU64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Budget {
+    // None until ESI reports it
+    remain: Option<u32>,
+    // Unix time at which ESI resets the budget
+    reset: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct GovernorState {
+    pub error_limit_remain: Option<u32>,
+    pub error_limit_reset: u64,
+    pub paused: bool,
+    pub throttled: bool,
+    pub requests_in_flight: usize,
+    pub max_concurrent_requests: usize,
+    pub delayed_requests: u64,
+}
+
+impl Governor {
+    pub fn new(config: ErrorLimit) -> Governor {
+        Governor {
+            permits: Semaphore::new(config.max_concurrent_requests.max(1)),
+            config: config,
+            budget: Mutex::new(Budget::default()),
+            delayed_requests: AtomicU64::new(0),
+        }
+    }
+
+    // Waits for as long as the error budget requires, then for a free
+    // request slot, so that delayed requests don't hold slots while asleep.
+    // Below the pause threshold requests wait out the budget window, below
+    // the slow threshold they are spread across what is left of it.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        let delay: Duration = self.delay();
+        if !delay.is_zero() {
+            self.delayed_requests.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(delay).await;
+        }
+        self.permits
+            .acquire()
+            .await
+            .unwrap()
+    }
+
+    // Records the error budget reported by any ESI response, including errors
+    pub fn observe(&self, headers: &HeaderMap) {
+        let remain: Option<u32> = parse_header(headers, ERROR_LIMIT_REMAIN_HEADER);
+        let reset: Option<u64> = parse_header(headers, ERROR_LIMIT_RESET_HEADER);
+        let (remain, reset) = match (remain, reset) {
+            (Some(remain), Some(reset)) => (remain, time::now() + reset),
+            _ => return,
+        };
+
+        let mut budget = self.budget.lock().unwrap();
+        let was_paused: bool = time::now() < budget.reset && matches!(
+            budget.remain,
+            Some(r) if r <= self.config.pause_below,
+        );
+        match budget.remain {
+            // Responses within a window may arrive out of order, so the
+            // lowest budget seen is the most recent one
+            Some(current) if time::now() < budget.reset => {
+                budget.remain = Some(current.min(remain));
+                budget.reset = budget.reset.max(reset);
+            },
+            _ => *budget = Budget {
+                remain: Some(remain),
+                reset: reset,
+            },
+        }
+
+        // Only changes are logged, as every response reports the budget
+        let remain: u32 = budget.remain.unwrap_or(remain);
+        let paused: bool = remain <= self.config.pause_below;
+        if paused && !was_paused {
+            println!(
+                "ESI error budget down to {}, pausing requests for {}s",
+                remain,
+                budget.reset.saturating_sub(time::now()),
+            );
+        } else if was_paused && !paused {
+            println!("ESI error budget back up to {}, resuming requests", remain);
+        }
+    }
+
+    pub fn state(&self) -> GovernorState {
+        let budget: Budget = self.current_budget();
+        let max_concurrent_requests: usize =
+            self.config.max_concurrent_requests.max(1);
+        GovernorState {
+            error_limit_remain: budget.remain,
+            error_limit_reset: budget.reset,
+            paused: matches!(
+                budget.remain,
+                Some(r) if r <= self.config.pause_below,
+            ),
+            throttled: matches!(
+                budget.remain,
+                Some(r) if r <= self.config.slow_below,
+            ),
+            requests_in_flight: max_concurrent_requests
+                - self.permits.available_permits(),
+            max_concurrent_requests: max_concurrent_requests,
+            delayed_requests: self.delayed_requests.load(Ordering::Relaxed),
+        }
+    }
+
+    fn delay(&self) -> Duration {
+        let budget: Budget = self.current_budget();
+        let (remain, until_reset) = match budget.remain {
+            Some(remain) => (remain, budget.reset.saturating_sub(time::now())),
+            None => return Duration::ZERO,
+        };
+        if remain <= self.config.pause_below {
+            Duration::from_secs(until_reset)
+        } else if remain <= self.config.slow_below {
+            Duration::from_millis(until_reset * 1000 / remain as u64)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    // The budget as last reported, forgotten once its window has reset
+    fn current_budget(&self) -> Budget {
+        let budget: Budget = *self.budget.lock().unwrap();
+        match time::now() < budget.reset {
+            true => budget,
+            false => Budget::default(),
+        }
+    }
+}
+
+fn parse_header<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
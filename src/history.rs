@@ -0,0 +1,169 @@
+#![allow(clippy::redundant_field_names)]
+
+use crate::{
+    TypeId,
+    config::HistoryRetention,
+    proto::{MarketOrdersReq, MarketSideStats, PricePoint},
+};
+
+use std::sync::Mutex;
+
+use rusqlite::{Connection, params};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS book_summaries (
+        market TEXT NOT NULL,
+        type_id INTEGER NOT NULL,
+        buy INTEGER NOT NULL,
+        time INTEGER NOT NULL,
+        resolution INTEGER NOT NULL,
+        best REAL NOT NULL,
+        volume INTEGER NOT NULL,
+        order_count INTEGER NOT NULL,
+        weighted_average REAL NOT NULL,
+        median REAL NOT NULL,
+        percentile REAL NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS book_summaries_by_book
+        ON book_summaries (market, type_id, buy, time);
+";
+
+// Records a summary of every refreshed book in an SQLite database. Rows are
+// kept as recorded for a while, then averaged into coarser rows, and finally
+// deleted, as set by the HistoryRetention.
+pub struct HistoryStore {
+    connection: Mutex<Connection>,
+    retention: HistoryRetention,
+}
+
+impl HistoryStore {
+    // Opens or creates the database, ":memory:" for one that is not persisted
+    pub fn open(
+        path: &str,
+        retention: HistoryRetention,
+    ) -> Result<HistoryStore, rusqlite::Error> {
+        let connection: Connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(HistoryStore {
+            connection: Mutex::new(connection),
+            retention: retention,
+        })
+    }
+
+    pub fn retention(&self) -> &HistoryRetention {
+        &self.retention
+    }
+
+    // Records the summaries of many books in one transaction, as a bulk
+    // region refreshes thousands at once
+    pub fn record(
+        &self,
+        time: u64,
+        summaries: impl IntoIterator<Item = (MarketOrdersReq, MarketSideStats)>,
+    ) -> Result<(), rusqlite::Error> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT INTO book_summaries (
+                    market, type_id, buy, time, resolution, best, volume,
+                    order_count, weighted_average, median, percentile
+                ) VALUES (?1, ?2, ?3, ?4, 0, ?5, ?6, ?7, ?8, ?9, ?10)",
+            )?;
+            for (req, stats) in summaries {
+                statement.execute(params![
+                    req.market,
+                    req.type_id,
+                    req.buy,
+                    time as i64,
+                    stats.best,
+                    stats.volume,
+                    stats.order_count,
+                    stats.weighted_average,
+                    stats.median,
+                    stats.percentile,
+                ])?;
+            }
+        }
+        transaction.commit()
+    }
+
+    // Averages recorded rows older than the raw retention into rows of the
+    // downsampled resolution, and deletes every row older than the retention
+    pub fn compact(&self, now: u64) -> Result<(), rusqlite::Error> {
+        let resolution: i64 = self.retention.resolution.max(1) as i64;
+        let raw_cutoff: i64 =
+            now.saturating_sub(self.retention.raw) as i64 / resolution * resolution;
+        let cutoff: i64 = now.saturating_sub(self.retention.retention) as i64;
+
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO book_summaries (
+                market, type_id, buy, time, resolution, best, volume,
+                order_count, weighted_average, median, percentile
+            )
+            SELECT market, type_id, buy, time / ?1 * ?1, ?1, AVG(best),
+                CAST(AVG(volume) AS INTEGER), CAST(AVG(order_count) AS INTEGER),
+                AVG(weighted_average), AVG(median), AVG(percentile)
+            FROM book_summaries
+            WHERE resolution = 0 AND time < ?2
+            GROUP BY market, type_id, buy, time / ?1",
+            params![resolution, raw_cutoff],
+        )?;
+        transaction.execute(
+            "DELETE FROM book_summaries WHERE resolution = 0 AND time < ?1",
+            params![raw_cutoff],
+        )?;
+        transaction.execute(
+            "DELETE FROM book_summaries WHERE time < ?1",
+            params![cutoff],
+        )?;
+        transaction.commit()
+    }
+
+    // Returns the summaries of a book between from and to inclusive, oldest
+    // first, averaged into buckets of resolution seconds unless it is 0
+    pub fn query(
+        &self,
+        market: &str,
+        type_id: TypeId,
+        buy: bool,
+        from: u64,
+        to: u64,
+        resolution: u64,
+    ) -> Result<Vec<PricePoint>, rusqlite::Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT time / ?6 * ?6 AS bucket, AVG(best),
+                CAST(AVG(volume) AS INTEGER), CAST(AVG(order_count) AS INTEGER),
+                AVG(weighted_average), AVG(median), AVG(percentile)
+            FROM book_summaries
+            WHERE market = ?1 AND type_id = ?2 AND buy = ?3
+                AND time >= ?4 AND time <= ?5
+            GROUP BY bucket
+            ORDER BY bucket",
+        )?;
+        // Every distinct time is its own bucket at resolution 1
+        let points = statement.query_map(
+            params![
+                market,
+                type_id,
+                buy,
+                from as i64,
+                to.min(i64::MAX as u64) as i64,
+                resolution.max(1) as i64,
+            ],
+            |row| Ok(PricePoint {
+                time: row.get::<_, i64>(0)? as u64,
+                best: row.get(1)?,
+                volume: row.get(2)?,
+                order_count: row.get(3)?,
+                weighted_average: row.get(4)?,
+                median: row.get(5)?,
+                percentile: row.get(6)?,
+            }),
+        )?;
+        points.collect()
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::redundant_field_names)]
+
 use crate::{
     {LocationId, TypeId},
     proto::*,
@@ -28,21 +30,63 @@ impl<T> Expirable<T> {
 pub struct AuthenticationResponse {
     pub access_token: String,
     pub expires_in: u64,
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct JwtClaims {
+    pub sub: String,
+    pub name: String,
+    #[serde(default)]
+    pub scp: Scopes,
+    pub exp: u64,
+}
+
+// SSO writes a lone scope as a string rather than a single element array
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Scopes {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Default for Scopes {
+    fn default() -> Self {
+        Scopes::Many(Vec::new())
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct StructureOrder {
+    pub duration: i32,
     pub is_buy_order: bool,
+    pub issued: String,
+    pub location_id: LocationId,
+    pub min_volume: i32,
+    pub order_id: i64,
     pub price: f64,
+    pub range: String,
     pub type_id: TypeId,
     pub volume_remain: i32,
+    pub volume_total: i32,
 }
 
 impl StructureOrder {
-    pub fn into_proto(self) -> MarketOrder {
-        MarketOrder {
-            quantity: self.volume_remain,
+    pub fn into_proto(self) -> MarketOrderDetailed {
+        MarketOrderDetailed {
+            order_id: self.order_id,
+            type_id: self.type_id,
+            location_id: self.location_id,
+            system_id: 0,
+            is_buy_order: self.is_buy_order,
             price: self.price,
+            volume_remain: self.volume_remain,
+            volume_total: self.volume_total,
+            min_volume: self.min_volume,
+            issued: self.issued,
+            duration: self.duration,
+            range: self.range,
+            remote: false,
         }
     }
 
@@ -57,16 +101,59 @@ impl StructureOrder {
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct StationOrder {
+    pub duration: i32,
+    pub is_buy_order: bool,
+    pub issued: String,
     pub location_id: LocationId,
+    pub min_volume: i32,
+    pub order_id: i64,
     pub price: f64,
+    pub range: String,
+    pub system_id: i32,
+    pub type_id: TypeId,
     pub volume_remain: i32,
+    pub volume_total: i32,
 }
 
 impl StationOrder {
-    pub fn into_proto(self) -> MarketOrder {
-        MarketOrder {
-            quantity: self.volume_remain,
+    pub fn into_proto(self) -> MarketOrderDetailed {
+        MarketOrderDetailed {
+            order_id: self.order_id,
+            type_id: self.type_id,
+            location_id: self.location_id,
+            system_id: self.system_id,
+            is_buy_order: self.is_buy_order,
             price: self.price,
+            volume_remain: self.volume_remain,
+            volume_total: self.volume_total,
+            min_volume: self.min_volume,
+            issued: self.issued,
+            duration: self.duration,
+            range: self.range,
+            remote: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MarketHistory {
+    pub average: f64,
+    pub date: String,
+    pub highest: f64,
+    pub lowest: f64,
+    pub order_count: i64,
+    pub volume: i64,
+}
+
+impl MarketHistory {
+    pub fn into_proto(self) -> MarketHistoryDay {
+        MarketHistoryDay {
+            date: self.date,
+            average: self.average,
+            highest: self.highest,
+            lowest: self.lowest,
+            volume: self.volume,
+            order_count: self.order_count,
         }
     }
 }
@@ -74,6 +161,8 @@ impl StationOrder {
 #[derive(Deserialize, Debug, Clone)]
 pub struct AdjustedPrice {
     pub adjusted_price: f64,
+    // ESI leaves this out for items that are never traded
+    pub average_price: Option<f64>,
     pub type_id: TypeId,
 }
 
@@ -81,6 +170,7 @@ impl AdjustedPrice {
     pub fn into_proto(self) -> AdjustedPriceRep {
         AdjustedPriceRep {
             adjusted_price: self.adjusted_price,
+            average_price: self.average_price,
         }
     }
 
@@ -0,0 +1,126 @@
+#![allow(clippy::redundant_field_names, clippy::redundant_closure)]
+
+use crate::{
+    config::Endpoints,
+    esi_client::Error,
+    json::{JwtClaims, Scopes},
+};
+
+use jsonwebtoken::{
+    Algorithm,
+    DecodingKey,
+    Validation,
+    jwk::JwkSet,
+    decode,
+    decode_header,
+};
+use tokio::sync::Mutex;
+
+pub const STRUCTURE_MARKETS_SCOPE: &str = "esi-markets.structure_markets.v1";
+
+// The character and scopes an SSO access token was granted for
+#[derive(Debug, Clone)]
+pub struct TokenInfo {
+    pub character_id: i64,
+    pub character_name: String,
+    pub scopes: Vec<String>,
+    pub expiry: u64,
+}
+
+// Verifies SSO access tokens against EVE's JWKS. A configured JWKS is used
+// as-is, otherwise it is fetched on first use and again whenever a token
+// is signed by a key it does not contain.
+pub struct Verifier {
+    client_id: String,
+    issuers: Vec<String>,
+    jwks_url: String,
+    jwks: Mutex<Option<JwkSet>>,
+    refetch: bool,
+}
+
+impl Verifier {
+    pub fn new(
+        client_id: &str,
+        jwks: Option<JwkSet>,
+        endpoints: &Endpoints,
+    ) -> Verifier {
+        Verifier {
+            client_id: client_id.to_string(),
+            issuers: endpoints.sso_issuers(),
+            jwks_url: endpoints.jwks_url(),
+            refetch: jwks.is_none(),
+            jwks: Mutex::new(jwks),
+        }
+    }
+
+    pub async fn verify(
+        &self,
+        client: &reqwest::Client,
+        access_token: &str,
+    ) -> Result<TokenInfo, Error> {
+        let kid: String = decode_header(access_token)
+            .map_err(|e| Error::InvalidAccessToken(e))?
+            .kid
+            .ok_or(Error::UnknownSigningKey)?;
+        let key: DecodingKey = self.decoding_key(client, &kid).await?;
+
+        let mut validation: Validation = Validation::new(Algorithm::RS256);
+        validation.set_issuer(&self.issuers);
+        validation.set_audience(&[&self.client_id]);
+        let claims: JwtClaims = decode(access_token, &key, &validation)
+            .map_err(|e| Error::InvalidAccessToken(e))?
+            .claims;
+
+        // sub is formatted as "CHARACTER:EVE:<character id>"
+        let character_id: i64 = claims.sub
+            .rsplit(':')
+            .next()
+            .and_then(|id| id.parse().ok())
+            .ok_or(Error::InvalidSubject(claims.sub.clone()))?;
+        let scopes: Vec<String> = match claims.scp {
+            Scopes::One(scope) => vec![scope],
+            Scopes::Many(scopes) => scopes,
+        };
+        if !scopes.iter().any(|s| s == STRUCTURE_MARKETS_SCOPE) {
+            return Err(Error::MissingScope(STRUCTURE_MARKETS_SCOPE));
+        }
+
+        Ok(TokenInfo {
+            character_id: character_id,
+            character_name: claims.name,
+            scopes: scopes,
+            expiry: claims.exp,
+        })
+    }
+
+    async fn decoding_key(
+        &self,
+        client: &reqwest::Client,
+        kid: &str,
+    ) -> Result<DecodingKey, Error> {
+        let mut jwks = self.jwks.lock().await;
+
+        let known: bool = jwks
+            .as_ref()
+            .and_then(|jwks| jwks.find(kid))
+            .is_some();
+        if !known && self.refetch {
+            *jwks = Some(client
+                .get(&self.jwks_url)
+                .send()
+                .await
+                .map_err(|e| Error::JwksRequestError(e))?
+                .json()
+                .await
+                .map_err(|e| Error::JwksRequestError(e))?
+            );
+        }
+
+        let jwk = jwks
+            .as_ref()
+            .and_then(|jwks| jwks.find(kid))
+            .ok_or(Error::UnknownSigningKey)?;
+        DecodingKey::from_jwk(jwk)
+            .map_err(|e| Error::InvalidAccessToken(e))
+    }
+}
@@ -0,0 +1,23 @@
+pub mod proto {
+    tonic::include_proto!("weve_esi_proto");
+}
+pub mod esi_client;
+pub mod service;
+pub mod config;
+mod cache;
+pub mod error;
+mod json;
+mod time;
+pub mod env;
+pub mod token_store;
+pub mod governor;
+pub mod history;
+mod jwt;
+mod stats;
+mod diff;
+
+pub type RefreshToken = String;
+pub type MarketName = String;
+pub type LocationId = i64;
+pub type RegionId = i32;
+pub type TypeId = i32;
@@ -1,26 +1,46 @@
-mod proto {
-    tonic::include_proto!("weve_esi_proto");
-}
-mod esi_client;
-mod service;
-mod config;
-mod cache;
-mod error;
-mod json;
-mod time;
-mod env;
+use weve_market::env;
 
-type RefreshToken = String;
-type MarketName = String;
-type LocationId = i64;
-type RegionId = i32;
-type TypeId = i32;
+const USAGE: &str = "usage: weve_market [--config <path>]";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let service = env::service_from_env().unwrap();
+    let config_path: Option<String> = match config_path() {
+        Ok(path) => path,
+        Err(usage) => {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        },
+    };
+
+    let service = match env::service_from_config(config_path.as_deref()) {
+        Ok(service) => service,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
 
-    service.serve().await.unwrap();
+    service.serve().await?;
 
     Ok(())
 }
+
+// The config file given with --config, or else the one named by the
+// WM_CONFIG_PATH environment variable
+fn config_path() -> Result<Option<String>, &'static str> {
+    let mut args = std::env::args().skip(1);
+    let mut path: Option<String> = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => path = Some(args.next().ok_or(USAGE)?),
+            a if a.starts_with("--config=") => {
+                path = Some(a["--config=".len()..].to_string());
+            },
+            _ => return Err(USAGE),
+        }
+    }
+    match path {
+        Some(path) => Ok(Some(path)),
+        None => Ok(std::env::var(env::CONFIG_PATH_VAR).ok()),
+    }
+}
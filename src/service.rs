@@ -1,58 +1,133 @@
+#![allow(clippy::redundant_field_names, clippy::redundant_closure)]
+
 use crate::{
     {LocationId, RegionId, TypeId},
     proto::weve_market_server::*,
-    esi_client::*,
+    esi_client::{Client, Error as EsiError},
+    governor::GovernorState,
+    history::HistoryStore,
+    jwt::TokenInfo,
     cache::Cache,
     error::Error,
     proto::*,
     json::*,
     config,
+    diff,
+    stats,
+    time,
 };
 
 use std::{
     collections::{HashMap, HashSet},
     hash::{Hash, Hasher},
-    sync::{Arc, RwLock},
+    sync::{Arc, RwLock, atomic::{AtomicU64, Ordering}},
     net::SocketAddr,
+    future::Future,
+    pin::Pin,
+    time::Duration,
     cmp::max,
 };
 
-use tonic::{Request, Response, Status, transport::Server};
-use tokio::sync::Mutex;
+use tonic::{
+    Request,
+    Response,
+    Status,
+    metadata::MetadataValue,
+    transport::Server,
+};
+use tokio::{
+    sync::{Mutex, OwnedMutexGuard, watch},
+    time::sleep,
+};
 use either::Either;
+use futures::{
+    future::{join_all, BoxFuture, FutureExt, Shared},
+    stream::{self, Stream, StreamExt},
+};
 
 type AdjustedPriceCache = Arc<Mutex<Cache<AdjustedPriceReq, AdjustedPriceRep>>>;
 type SystemIndexCache = Arc<Mutex<Cache<SystemIndexReq, SystemIndexRep>>>;
+type MarketOrdersCache = Arc<Mutex<Cache<MarketOrdersReq, MarketOrdersDetailedRep>>>;
+type MarketHistoryCache = Arc<Mutex<Cache<(RegionId, TypeId), MarketHistoryRep>>>;
+type OrderChanges = HashMap<MarketOrdersReq, OrderChangesRep>;
+type Subscriptions = HashMap<MarketOrdersReq, Arc<watch::Sender<MarketOrdersUpdate>>>;
+type MarketOrdersUpdateStream = Pin<Box<
+    dyn Stream<Item = Result<MarketOrdersUpdate, Status>> + Send
+>>;
 type StationMarketOrderCache = HashMap<
     RegionId,
     Arc<RwLock<HashMap<
         (TypeId, bool),
-        Arc<Mutex<Cache<MarketOrdersReq, MarketOrdersRep>>>,
+        Arc<Mutex<Cache<MarketOrdersReq, MarketOrdersDetailedRep>>>,
     >>>,
 >;
+type BulkWalk = Shared<BoxFuture<'static, Result<(), Status>>>;
 type StructureMarketOrderCache = HashMap<
 LocationId,
-    Arc<Mutex<Cache<MarketOrdersReq, MarketOrdersRep>>>,
+    Arc<Mutex<Cache<MarketOrdersReq, MarketOrdersDetailedRep>>>,
 >;
 
+// Metadata key set on responses served from an expired cache entry
+const STALE_METADATA_KEY: &str = "stale";
+// Longest numeric range a buy order can have
+const MAX_BUY_ORDER_JUMPS: u32 = 40;
+// Seconds to wait before retrying a failed prefetch
+const PREFETCH_RETRY_DELAY: u64 = 60;
+// Seconds to wait before refreshing a subscribed book again after an error
+const SUBSCRIPTION_RETRY_DELAY: u64 = 10;
+// Seconds between compactions of the history store
+const HISTORY_COMPACTION_INTERVAL: u64 = 60 * 60;
+// Most regional histories kept at once, before expired ones are dropped
+const MAX_MARKET_HISTORIES: usize = 10000;
+// Seconds a type ESI has no history for is answered without asking again
+const MISSING_HISTORY_CACHE_TIME: u64 = 60 * 60;
+
+#[derive(Clone)]
 pub struct Service {
-    esi_client: Client,
-    structure_cache: StructureMarketOrderCache,
-    station_cache: StationMarketOrderCache,
+    esi_client: Arc<Client>,
+    structure_cache: Arc<StructureMarketOrderCache>,
+    station_cache: Arc<StationMarketOrderCache>,
+    bulk_regions: Arc<HashMap<RegionId, BulkRegion>>,
     adjusted_price_cache: AdjustedPriceCache,
     system_index_cache: SystemIndexCache,
-    markets: config::Markets,
-    stations: HashSet<(RegionId, LocationId)>,
-    station_markets: HashMap<LocationId, String>,
+    market_history_cache: Arc<RwLock<HashMap<(RegionId, TypeId), MarketHistoryCache>>>,
+    markets: Arc<config::Markets>,
+    stations: Arc<HashSet<(RegionId, LocationId)>>,
+    station_markets: Arc<HashMap<LocationId, String>>,
     min_cache_time: config::MinCacheDuration,
+    max_staleness: u64,
+    prefetch: Arc<config::Prefetch>,
+    buy_range: Option<Arc<config::BuyRange>>,
+    subscriptions: Arc<std::sync::Mutex<Subscriptions>>,
+    order_changes: Arc<std::sync::Mutex<OrderChanges>>,
+    history: Option<Arc<HistoryStore>>,
+    strict: bool,
     address: Option<SocketAddr>,
 }
 
+// A region whose orders are fetched all at once and split into the per-type
+// caches of its stations, rather than fetched a type at a time.
+#[derive(Default)]
+struct BulkRegion {
+    // Expiry of the last stored dump, up to which a type it had no orders
+    // for has none
+    expiry: AtomicU64,
+    // The walk underway, which every request and prefetch waits on
+    walk: std::sync::Mutex<Option<BulkWalk>>,
+}
+
 impl Service {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         esi_client: Client,
         markets: config::Markets,
         min_cache_time: config::MinCacheDuration,
+        max_staleness: u64,
+        prefetch: config::Prefetch,
+        buy_range: Option<config::BuyRange>,
+        bulk_regions: HashSet<RegionId>,
+        history: Option<HistoryStore>,
+        strict: bool,
         address: SocketAddr,
     ) -> Service {
         let stations = markets.stations();
@@ -80,16 +155,37 @@ impl Service {
             };
         }
 
+        let mut bulk = HashMap::new();
+        for region_id in bulk_regions.into_iter() {
+            if !station_cache.contains_key(&region_id) {
+                println!(
+                    "Skipping bulk ingestion of [{}]: no station market in region",
+                    region_id,
+                );
+                continue;
+            }
+            bulk.insert(region_id, BulkRegion::default());
+        }
+
         Service {
-            esi_client: esi_client,
-            structure_cache: structure_cache,
-            station_cache: station_cache,
+            esi_client: Arc::new(esi_client),
+            structure_cache: Arc::new(structure_cache),
+            station_cache: Arc::new(station_cache),
+            bulk_regions: Arc::new(bulk),
             adjusted_price_cache: adjusted_price_cache,
             system_index_cache: system_index_cache,
-            markets: markets,
-            stations: stations,
-            station_markets: station_markets,
+            market_history_cache: Arc::new(RwLock::new(HashMap::new())),
+            markets: Arc::new(markets),
+            stations: Arc::new(stations),
+            station_markets: Arc::new(station_markets),
             min_cache_time: min_cache_time,
+            max_staleness: max_staleness,
+            prefetch: Arc::new(prefetch),
+            buy_range: buy_range.map(|b| Arc::new(b)),
+            subscriptions: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            order_changes: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            history: history.map(|h| Arc::new(h)),
+            strict: strict,
             address: Some(address),
         }
     }
@@ -99,6 +195,12 @@ impl Service {
             .address
             .take()
             .unwrap();
+        if self.prefetch.enabled {
+            self.start_prefetch();
+        }
+        if let Some(history) = &self.history {
+            start_history_compaction(history.clone());
+        }
         Server::builder()
             .add_service(WeveMarketServer::new(self))
             .serve(address)
@@ -106,141 +208,844 @@ impl Service {
             .map_err(|e| Error::ServiceServeError(e))
     }
 
-    async fn station_orders(
+    // In strict mode unknown ids are reported as errors, otherwise they
+    // get an empty reply.
+    #[allow(clippy::result_large_err)]
+    fn not_found<T: Default>(
+        &self,
+        err: Error,
+        stale: bool,
+    ) -> Result<Response<T>, Status> {
+        match self.strict {
+            true => Err(err.into()),
+            false => Ok(respond(T::default(), stale)),
+        }
+    }
+
+    // Locks the cache, making sure it holds something servable. If it has
+    // expired but is within max_staleness, the old contents are returned
+    // as stale and a single background refresh is started. Otherwise the
+    // refresh happens inline while holding the lock.
+    async fn lock_cache<K, V, D, F, Fut, S>(
+        &self,
+        cache_ref: Arc<Mutex<Cache<K, V>>>,
+        fetch: F,
+        store: S,
+    ) -> Result<(OwnedMutexGuard<Cache<K, V>>, bool), Status>
+    where
+        K: Eq + Hash + Send + 'static,
+        V: Send + 'static,
+        D: Send + 'static,
+        F: FnOnce(Arc<Client>) -> Fut,
+        Fut: Future<Output = Result<D, EsiError>> + Send + 'static,
+        S: FnOnce(&Service, &mut Cache<K, V>, D) + Send + 'static,
+    {
+        let mut cache = cache_ref.clone().lock_owned().await;
+
+        if !cache.expired() {
+            return Ok((cache, false));
+        }
+
+        if cache.stale(self.max_staleness) {
+            if cache.begin_refresh() {
+                let service: Service = self.clone();
+                let fetching = fetch(self.esi_client.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = service
+                        .finish_refresh(cache_ref, fetching, store)
+                        .await
+                    {
+                        println!(
+                            "Background refresh failed, serving stale: {}",
+                            e,
+                        );
+                    }
+                });
+            }
+            return Ok((cache, true));
+        }
+
+        let data: D = fetch(self.esi_client.clone())
+            .await
+            .map_err(|e| Error::EsiClientError(e))?;
+        store(self, &mut cache, data);
+        Ok((cache, false))
+    }
+
+    // Returns the walk of the bulk region underway, starting one if there is
+    // none. The walk fetches every order in the region and stores them in
+    // the caches of its types as a task of its own, so that requests giving
+    // up on it neither cancel it nor start another, and all of them get its
+    // error if it fails.
+    fn walk_bulk_region(&self, region_id: RegionId) -> BulkWalk {
+        let mut walk = self.bulk_regions[&region_id].walk.lock().unwrap();
+        if let Some(walk) = walk.as_ref() {
+            return walk.clone();
+        }
+
+        let service: Service = self.clone();
+        let task = tokio::spawn(async move {
+            let result: Result<(), Status> = match service
+                .esi_client
+                .get_region_orders(&region_id)
+                .await
+            {
+                Ok(raws) => {
+                    service.store_region_orders(raws, region_id).await;
+                    Ok(())
+                },
+                Err(e) => {
+                    println!("Walk of region [{}] failed: {}", region_id, e);
+                    Err(Error::EsiClientError(e).into())
+                },
+            };
+            *service.bulk_regions[&region_id].walk.lock().unwrap() = None;
+            result
+        });
+        let shared: BulkWalk = async move {
+            match task.await {
+                Ok(result) => result,
+                Err(e) => Err(Status::internal(e.to_string())),
+            }
+        }
+            .boxed()
+            .shared();
+        *walk = Some(shared.clone());
+        shared
+    }
+
+    // Completes a refresh started with Cache::begin_refresh. The cache is
+    // only locked to store the result, and is left untouched on error.
+    async fn finish_refresh<K, V, D, Fut, S>(
+        &self,
+        cache_ref: Arc<Mutex<Cache<K, V>>>,
+        fetching: Fut,
+        store: S,
+    ) -> Result<(), EsiError>
+    where
+        K: Eq + Hash,
+        Fut: Future<Output = Result<D, EsiError>>,
+        S: FnOnce(&Service, &mut Cache<K, V>, D),
+    {
+        let data = fetching.await;
+        let mut cache = cache_ref.lock().await;
+        let result = data.map(|data| store(self, &mut cache, data));
+        cache.end_refresh();
+        result
+    }
+
+    // Spawns a prefetch task for every structure market, the adjusted
+    // prices, the system indices, and each side of every watchlist entry.
+    fn start_prefetch(&self) {
+        for (market, (location_id, either)) in self.markets.iter() {
+            let refresh_token: Option<String> = match either {
+                Either::Right(refresh_token) => refresh_token.clone(),
+                Either::Left(_) => continue,
+            };
+            let (location_id, market) = (*location_id, market.clone());
+            self.spawn_prefetch(
+                self.structure_cache[&location_id].clone(),
+                move |client| {
+                    let refresh_token = refresh_token.clone();
+                    async move {
+                        client
+                            .get_structure_orders(
+                                &location_id,
+                                refresh_token.as_deref(),
+                            )
+                            .await
+                    }
+                },
+                move |service, cache, raws| service.store_structure_orders(
+                    cache,
+                    raws,
+                    &market,
+                ),
+            );
+        }
+
+        self.spawn_prefetch(
+            self.adjusted_price_cache.clone(),
+            |client| async move { client.get_adjusted_price().await },
+            |service, cache, raws| service.store_adjusted_prices(cache, raws),
+        );
+
+        self.spawn_prefetch(
+            self.system_index_cache.clone(),
+            |client| async move { client.get_system_index().await },
+            |service, cache, raws| service.store_system_indices(cache, raws),
+        );
+
+        for region_id in self.bulk_regions.keys() {
+            self.spawn_bulk_prefetch(*region_id);
+        }
+
+        for (region_id, type_id) in self.prefetch.watchlist.iter() {
+            if self.bulk_regions.contains_key(region_id) {
+                continue;
+            }
+            if !self.station_cache.contains_key(region_id) {
+                println!(
+                    "Skipping prefetch of [{}] [{}]: no station market in region",
+                    region_id,
+                    type_id,
+                );
+                continue;
+            }
+            for buy in [true, false] {
+                let (region_id, type_id) = (*region_id, *type_id);
+                self.spawn_prefetch(
+                    self.station_cache_ref(&region_id, &(type_id, buy)),
+                    move |client| async move {
+                        client
+                            .get_station_orders(
+                                &region_id,
+                                order_type(buy),
+                                &type_id,
+                            )
+                            .await
+                    },
+                    move |service, cache, raws| service.store_station_orders(
+                        cache,
+                        raws,
+                        &region_id,
+                    ),
+                );
+            }
+        }
+    }
+
+    // Refreshes the cache shortly after each time it expires, skipping any
+    // expiry where a refresh is already underway.
+    fn spawn_prefetch<K, V, D, F, Fut, S>(
+        &self,
+        cache_ref: Arc<Mutex<Cache<K, V>>>,
+        fetch: F,
+        store: S,
+    )
+    where
+        K: Eq + Hash + Send + 'static,
+        V: Send + 'static,
+        D: Send + 'static,
+        F: Fn(Arc<Client>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<D, EsiError>> + Send + 'static,
+        S: Fn(&Service, &mut Cache<K, V>, D) + Send + Sync + 'static,
+    {
+        let service: Service = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let expiry: u64 = cache_ref.lock().await.expiry();
+                sleep(Duration::from_secs(
+                    expiry.saturating_sub(time::now())
+                        + 1
+                        + service.prefetch.jitter()
+                ))
+                    .await;
+
+                if !cache_ref.lock().await.begin_refresh() {
+                    continue;
+                }
+                if let Err(e) = service
+                    .finish_refresh(
+                        cache_ref.clone(),
+                        fetch(service.esi_client.clone()),
+                        &store,
+                    )
+                    .await
+                {
+                    println!("Prefetch failed: {}", e);
+                    sleep(Duration::from_secs(
+                        PREFETCH_RETRY_DELAY + service.prefetch.jitter()
+                    ))
+                        .await;
+                }
+            }
+        });
+    }
+
+    // Walks the bulk region whenever its last dump expires, through the same
+    // walk requests for it wait on.
+    fn spawn_bulk_prefetch(&self, region_id: RegionId) {
+        let service: Service = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let expiry: u64 = service.bulk_regions[&region_id]
+                    .expiry
+                    .load(Ordering::Relaxed);
+                sleep(Duration::from_secs(
+                    expiry.saturating_sub(time::now())
+                        + 1
+                        + service.prefetch.jitter()
+                ))
+                    .await;
+
+                // The walk reports its own failure
+                if service.walk_bulk_region(region_id).await.is_err() {
+                    sleep(Duration::from_secs(
+                        PREFETCH_RETRY_DELAY + service.prefetch.jitter()
+                    ))
+                        .await;
+                }
+            }
+        });
+    }
+
+    // Returns the cache for the given side of a type in a station region,
+    // creating it if it does not exist yet. A type missing from the last
+    // dump of a bulk region starts out with no orders until the dump expires.
+    fn station_cache_ref(
         &self,
-        req: MarketOrdersReq,
-        location_id: &LocationId,
         region_id: &RegionId,
-    ) -> Result<Response<MarketOrdersRep>, Status> {
+        k: &(TypeId, bool),
+    ) -> Arc<Mutex<Cache<MarketOrdersReq, MarketOrdersDetailedRep>>> {
         let region_map_ref = self.station_cache[region_id].clone();
-        let k = &(req.type_id, req.buy);
 
         let cache_ref = region_map_ref
             .read()
             .unwrap()
             .get(k)
-            .map(|c| c.clone());
-        let cache_ref = match cache_ref {
+            .cloned();
+        match cache_ref {
             Some(c) => c,
             None => {
                 let mut region_map = region_map_ref.write().unwrap();
-                region_map.insert(*k, Arc::new(Mutex::new(Cache::new())));
-                region_map[k].clone()
+                region_map
+                    .entry(*k)
+                    .or_insert_with(|| {
+                        let mut cache = Cache::new();
+                        if let Some(bulk) = self.bulk_regions.get(region_id) {
+                            cache.update_expiry(
+                                bulk.expiry.load(Ordering::Relaxed),
+                            );
+                        }
+                        Arc::new(Mutex::new(cache))
+                    })
+                    .clone()
             }
-        };
-        let mut cache = cache_ref.lock().await;
+        }
+    }
 
-        if !cache.expired() {
-            match cache.get(&req) {
-                Some(rep) => return Ok(Response::new(rep.clone())),
-                None => return Ok(Response::new(MarketOrdersRep {
-                    market_orders: Vec::new(),
-                })),
-            };
+    async fn station_orders(
+        &self,
+        req: MarketOrdersReq,
+        region_id: &RegionId,
+    ) -> Result<Response<MarketOrdersDetailedRep>, Status> {
+        let cache_ref = self.station_cache_ref(
+            region_id,
+            &(req.type_id, req.buy),
+        );
+
+        if self.bulk_regions.contains_key(region_id) {
+            return self.bulk_station_orders(req, cache_ref, *region_id).await;
         }
 
-        let raws: Expirable<Vec<StationOrder>> = self
-            .esi_client
-            .get_station_orders(
-                &region_id,
-                match req.buy {
-                    true => "buy",
-                    false => "sell",
+        let region_id: RegionId = *region_id;
+        let (type_id, buy) = (req.type_id, req.buy);
+        let (cache, stale) = self
+            .lock_cache(
+                cache_ref,
+                move |client| async move {
+                    client
+                        .get_station_orders(
+                            &region_id,
+                            order_type(buy),
+                            &type_id,
+                        )
+                        .await
                 },
-                &req.type_id,
+                move |service, cache, raws| service.store_station_orders(
+                    cache,
+                    raws,
+                    &region_id,
+                ),
             )
-            .await
-            .unwrap();
+            .await?;
+
+        Ok(respond(
+            cache.get_forced(&req).cloned().unwrap_or_default(),
+            stale,
+        ))
+    }
+
+    // Like station_orders, but an expired book is refreshed by walking the
+    // whole bulk region, without holding the book's cache lock meanwhile.
+    async fn bulk_station_orders(
+        &self,
+        req: MarketOrdersReq,
+        cache_ref: MarketOrdersCache,
+        region_id: RegionId,
+    ) -> Result<Response<MarketOrdersDetailedRep>, Status> {
+        {
+            let cache = cache_ref.lock().await;
+            let stale: bool = cache.stale(self.max_staleness);
+            if !cache.expired() || stale {
+                if stale {
+                    drop(self.walk_bulk_region(region_id));
+                }
+                return Ok(respond(
+                    cache.get_forced(&req).cloned().unwrap_or_default(),
+                    stale,
+                ));
+            }
+        }
+
+        self.walk_bulk_region(region_id).await?;
+        let cache = cache_ref.lock().await;
+        Ok(respond(
+            cache.get_forced(&req).cloned().unwrap_or_default(),
+            false,
+        ))
+    }
+
+    fn market_history_cache_ref(
+        &self,
+        k: &(RegionId, TypeId),
+    ) -> MarketHistoryCache {
+        let cache_ref = self.market_history_cache
+            .read()
+            .unwrap()
+            .get(k)
+            .cloned();
+        if let Some(c) = cache_ref {
+            return c;
+        }
+
+        let mut caches = self.market_history_cache.write().unwrap();
+        if caches.len() >= MAX_MARKET_HISTORIES && !caches.contains_key(k) {
+            // Expired histories are fetched again on their next request
+            // anyway, and ones being refreshed are locked and kept
+            caches.retain(|_, c| !matches!(
+                c.try_lock(),
+                Ok(cache) if cache.expired(),
+            ));
+        }
+        caches
+            .entry(*k)
+            .or_insert_with(|| Arc::new(Mutex::new(Cache::new())))
+            .clone()
+    }
+
+    // Drops the entry of a history that has never been fetched, so that
+    // requests for types ESI does not know don't pile up in the map
+    fn forget_market_history(&self, k: &(RegionId, TypeId)) {
+        let mut caches = self.market_history_cache.write().unwrap();
+        let unfetched: bool = matches!(
+            caches.get(k).map(|c| c.try_lock()),
+            Some(Ok(cache)) if cache.expiry() == 0,
+        );
+        if unfetched {
+            caches.remove(k);
+        }
+    }
 
-        cache.clear_and_update_expiry(max(
+    // Resolves the market to its station region or structure and returns
+    // the full orders for the request
+    async fn orders(
+        &self,
+        req: MarketOrdersReq,
+    ) -> Result<Response<MarketOrdersDetailedRep>, Status> {
+        match self.markets.get(&req.market) {
+            Some((_, Either::Left(region_id))) => self
+                .station_orders(req, region_id)
+                .await,
+            Some((location_id, Either::Right(refresh_token))) => self
+                .structure_orders(req, location_id, refresh_token.as_deref())
+                .await,
+            None => self.not_found(Error::MarketNotFound(req.market), false),
+        }
+    }
+
+    // Returns the cache holding the orders for the request, if its market
+    // exists
+    fn orders_cache_ref(&self, req: &MarketOrdersReq) -> Option<MarketOrdersCache> {
+        match self.markets.get(&req.market)? {
+            (_, Either::Left(region_id)) => Some(self.station_cache_ref(
+                region_id,
+                &(req.type_id, req.buy),
+            )),
+            (location_id, Either::Right(_)) => Some(
+                self.structure_cache[location_id].clone(),
+            ),
+        }
+    }
+
+    // Returns a receiver of every refresh of the book, starting a task that
+    // keeps the book fresh if it is the first subscriber. All subscribers of
+    // a book share the task and its fetches.
+    fn subscribe(
+        &self,
+        req: MarketOrdersReq,
+        cache_ref: MarketOrdersCache,
+        initial: MarketOrdersUpdate,
+    ) -> watch::Receiver<MarketOrdersUpdate> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if let Some(sender) = subscriptions.get(&req) {
+            return sender.subscribe();
+        }
+        let (sender, receiver) = watch::channel(initial);
+        let sender = Arc::new(sender);
+        subscriptions.insert(req.clone(), sender.clone());
+
+        let service: Service = self.clone();
+        tokio::spawn(async move {
+            service.keep_subscription_fresh(req, cache_ref, sender).await
+        });
+        receiver
+    }
+
+    // Refreshes the book shortly after each time it expires, sending it to
+    // subscribers whenever the orders or expiry change, until none are left
+    async fn keep_subscription_fresh(
+        &self,
+        req: MarketOrdersReq,
+        cache_ref: MarketOrdersCache,
+        sender: Arc<watch::Sender<MarketOrdersUpdate>>,
+    ) {
+        let mut delay: u64 = subscription_delay(sender.borrow().expires);
+        loop {
+            tokio::select! {
+                _ = sleep(Duration::from_secs(delay)) => {},
+                _ = sender.closed() => {},
+            }
+            {
+                let mut subscriptions = self.subscriptions.lock().unwrap();
+                if sender.receiver_count() == 0 {
+                    subscriptions.remove(&req);
+                    return;
+                }
+            }
+
+            match self.orders(req.clone()).await {
+                Ok(rep) => {
+                    let update: MarketOrdersUpdate = MarketOrdersUpdate {
+                        market_orders: rep.into_inner().market_orders,
+                        expires: cache_ref.lock().await.expiry(),
+                    };
+                    delay = subscription_delay(update.expires);
+                    sender.send_if_modified(|current| match *current == update {
+                        true => false,
+                        false => {
+                            *current = update;
+                            true
+                        },
+                    });
+                },
+                Err(e) => {
+                    println!(
+                        "Subscription refresh of [{}] [{}] [{}] failed: {}",
+                        req.type_id,
+                        req.market,
+                        req.buy,
+                        e.message(),
+                    );
+                    delay = SUBSCRIPTION_RETRY_DELAY;
+                },
+            }
+        }
+    }
+
+    // Stores the orders of every configured station in the region, split
+    // by type and side
+    fn store_station_orders(
+        &self,
+        cache: &mut Cache<MarketOrdersReq, MarketOrdersDetailedRep>,
+        raws: Expirable<Option<Vec<StationOrder>>>,
+        region_id: &RegionId,
+    ) {
+        let reaches: HashMap<LocationId, HashMap<i32, u32>> = match raws
+            .inner
+            .as_ref()
+            .is_some_and(|raws| raws.iter().any(|raw| raw.is_buy_order))
+        {
+            true => self.buy_order_reach(region_id),
+            false => HashMap::new(),
+        };
+        self.store_station_book(cache, raws, region_id, &reaches);
+        self.record_history(cache);
+    }
+
+    // Stores every order of a bulk region in the caches of their types, and
+    // empties the cache of every type the region no longer has orders for.
+    // If ESI reported the region unchanged, every cache is kept as it is.
+    async fn store_region_orders(
+        &self,
+        raws: Expirable<Option<Vec<StationOrder>>>,
+        region_id: RegionId,
+    ) {
+        let expires_in: u64 = raws.expires_in;
+        let mut books: Option<HashMap<(TypeId, bool), Vec<StationOrder>>> =
+            raws.into_inner().map(|raws| {
+                let mut books: HashMap<(TypeId, bool), Vec<StationOrder>> =
+                    HashMap::new();
+                for raw in raws.into_iter() {
+                    books
+                        .entry((raw.type_id, raw.is_buy_order))
+                        .or_default()
+                        .push(raw);
+                }
+                books
+            });
+        let reaches: HashMap<LocationId, HashMap<i32, u32>> = match books
+            .as_ref()
+            .is_some_and(|books| books.keys().any(|(_, buy)| *buy))
+        {
+            true => self.buy_order_reach(&region_id),
+            false => HashMap::new(),
+        };
+
+        let mut ks: HashSet<(TypeId, bool)> = self.station_cache[&region_id]
+            .read()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect();
+        if let Some(books) = &books {
+            ks.extend(books.keys().copied());
+        }
+
+        let mut summaries: Vec<(MarketOrdersReq, MarketSideStats)> = Vec::new();
+        for k in ks.into_iter() {
+            let book: Option<Vec<StationOrder>> = books
+                .as_mut()
+                .map(|books| books.remove(&k).unwrap_or_default());
+            let cache_ref = self.station_cache_ref(&region_id, &k);
+            let mut cache = cache_ref.lock().await;
+            self.store_station_book(
+                &mut cache,
+                Expirable::new(book, expires_in),
+                &region_id,
+                &reaches,
+            );
+            if self.history.is_some() {
+                summaries.extend(history_summaries(&cache));
+            }
+        }
+        self.record_summaries(summaries);
+
+        self.bulk_regions[&region_id].expiry.store(
+            time::now() + max(
+                expires_in,
+                self.min_cache_time.station_market_orders(),
+            ),
+            Ordering::Relaxed,
+        );
+    }
+
+    // Stores the orders of one side of a type in its station cache, along
+    // with the remote buy orders reaching each station.
+    fn store_station_book(
+        &self,
+        cache: &mut Cache<MarketOrdersReq, MarketOrdersDetailedRep>,
+        raws: Expirable<Option<Vec<StationOrder>>>,
+        region_id: &RegionId,
+        reaches: &HashMap<LocationId, HashMap<i32, u32>>,
+    ) {
+        let expiry: u64 = max(
             raws.expires_in,
             self.min_cache_time.station_market_orders(),
-        ));
-
-        let mut rep: MarketOrdersRep = MarketOrdersRep {
-            market_orders: Vec::with_capacity(raws.inner.len()),
+        );
+        let had_snapshot: bool = cache.expiry() != 0;
+        let raws: Vec<StationOrder> = match raws.into_inner() {
+            Some(raws) => raws,
+            None => {
+                cache.update_expiry(expiry);
+                return self.record_order_changes(None, cache);
+            },
         };
-        let mut reps: HashMap<LocationId, MarketOrdersRep> = HashMap::new();
-        for raw in raws.into_inner().into_iter() {
+        let previous = cache.clear_and_update_expiry(expiry);
+
+        let mut reps: HashMap<(LocationId, TypeId, bool), MarketOrdersDetailedRep> =
+            HashMap::new();
+        for raw in raws.into_iter() {
             let k = &raw.location_id;
-            if k == location_id {
-                rep.market_orders.push(raw.into_proto());
+            let (type_id, buy) = (raw.type_id, raw.is_buy_order);
+            if self.stations.contains(&(*region_id, *k)) {
+                reps.entry((*k, type_id, buy)).or_default().market_orders.push(
+                    raw.clone().into_proto(),
+                );
             }
-            else if self.stations.contains(&(*region_id, *k)) {
-                match reps.get_mut(k) {
-                    Some(r) => r
+            if !buy {
+                continue;
+            }
+            for (location_id, distances) in reaches.iter() {
+                if location_id != k
+                    && in_range(&raw.range, distances.get(&raw.system_id))
+                {
+                    let mut order: MarketOrderDetailed = raw.clone().into_proto();
+                    order.remote = true;
+                    reps.entry((*location_id, type_id, buy))
+                        .or_default()
                         .market_orders
-                        .push(raw.into_proto()),
-                    None => reps
-                        .insert(*k, MarketOrdersRep {
-                            market_orders: vec![raw.into_proto()],
-                        })
-                        .map_or_else(|| (), |_| ()),
-                };
+                        .push(order);
+                }
             }
         }
 
-        cache.insert(req.clone(), rep);
-        for (location_id, rep) in reps.into_iter() {
+        for ((location_id, type_id, buy), rep) in reps.into_iter() {
             cache.insert(
                 MarketOrdersReq {
-                    type_id: req.type_id,
+                    type_id: type_id,
                     market: self.station_markets[&location_id].clone(),
-                    buy: req.buy,
+                    buy: buy,
                 },
                 rep,
             )
         }
+        self.record_order_changes(had_snapshot.then_some(&previous), cache);
+    }
 
-        match cache.get_forced(&req) {
-            Some(rep) => Ok(Response::new(rep.clone())),
-            None => Ok(Response::new(MarketOrdersRep {
-                market_orders: Vec::new(),
-            }))
+    // Records the changes to every book either snapshot holds. Without a
+    // previous snapshot, or if ESI reported the books unchanged, the changes
+    // are empty.
+    fn record_order_changes(
+        &self,
+        previous: Option<&HashMap<MarketOrdersReq, MarketOrdersDetailedRep>>,
+        cache: &Cache<MarketOrdersReq, MarketOrdersDetailedRep>,
+    ) {
+        let now: u64 = time::now();
+        let reqs: HashSet<&MarketOrdersReq> = cache
+            .iter()
+            .map(|(req, _)| req)
+            .chain(previous.into_iter().flat_map(|p| p.keys()))
+            .collect();
+
+        let mut order_changes = self.order_changes.lock().unwrap();
+        for req in reqs {
+            let mut changes: OrderChangesRep = match previous {
+                Some(previous) => diff::order_changes(
+                    previous.get(req),
+                    cache.get_forced(req),
+                ),
+                None => OrderChangesRep::default(),
+            };
+            changes.from = order_changes.get(req).map_or(0, |c| c.to);
+            changes.to = now;
+            order_changes.insert(req.clone(), changes);
+        }
+    }
+
+    // Records a summary of every book in the cache, if history is enabled.
+    fn record_history(
+        &self,
+        cache: &Cache<MarketOrdersReq, MarketOrdersDetailedRep>,
+    ) {
+        if self.history.is_some() {
+            self.record_summaries(history_summaries(cache));
         }
     }
 
+    // Records the summaries, if history is enabled. They are written on a
+    // blocking thread, so that neither the runtime nor any cache lock wait
+    // for SQLite.
+    fn record_summaries(
+        &self,
+        summaries: Vec<(MarketOrdersReq, MarketSideStats)>,
+    ) {
+        let history: Arc<HistoryStore> = match &self.history {
+            Some(history) => history.clone(),
+            None => return,
+        };
+        let now: u64 = time::now();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = history.record(now, summaries) {
+                println!("Failed to record history: {}", e);
+            }
+        });
+    }
+
+    // Returns the jumps to every system within the longest buy order range
+    // of each station in the region, for the stations whose system is known.
+    // Empty unless range-aware buy orders are enabled.
+    fn buy_order_reach(
+        &self,
+        region_id: &RegionId,
+    ) -> HashMap<LocationId, HashMap<i32, u32>> {
+        let buy_range: &config::BuyRange = match &self.buy_range {
+            Some(buy_range) => buy_range,
+            None => return HashMap::new(),
+        };
+        self.stations
+            .iter()
+            .filter(|(r, _)| r == region_id)
+            .filter_map(|(_, location_id)| buy_range
+                .station_systems
+                .get(location_id)
+                .map(|system_id| (
+                    *location_id,
+                    buy_range.distances(*system_id, MAX_BUY_ORDER_JUMPS),
+                ))
+            )
+            .collect()
+    }
+
     async fn structure_orders(
         &self,
         req: MarketOrdersReq,
-        location_id: &i64,
+        location_id: &LocationId,
         refresh_token: Option<&str>,
-    ) -> Result<Response<MarketOrdersRep>, Status> {
+    ) -> Result<Response<MarketOrdersDetailedRep>, Status> {
         let cache_ref = self.structure_cache[location_id].clone();
-        let mut cache = cache_ref.lock().await;
 
-        if !cache.expired() {
-            match cache.get(&req) {
-                Some(rep) => return Ok(Response::new(rep.clone())),
-                None => return Ok(Response::new(MarketOrdersRep {
-                    market_orders: Vec::new(),
-                })),
-            };
-        }
-
-        let raws: Expirable<Vec<StructureOrder>> = self
-            .esi_client
-            .get_structure_orders(
-                location_id,
-                refresh_token,
+        let location_id: LocationId = *location_id;
+        let refresh_token: Option<String> = refresh_token
+            .map(|s| s.to_string());
+        let market: String = req.market.clone();
+        let (cache, stale) = self
+            .lock_cache(
+                cache_ref,
+                move |client| async move {
+                    client
+                        .get_structure_orders(
+                            &location_id,
+                            refresh_token.as_deref(),
+                        )
+                        .await
+                },
+                move |service, cache, raws| service.store_structure_orders(
+                    cache,
+                    raws,
+                    &market,
+                ),
             )
-            .await
-            .unwrap();
+            .await?;
+
+        Ok(respond(
+            cache.get_forced(&req).cloned().unwrap_or_default(),
+            stale,
+        ))
+    }
 
-        cache.clear_and_update_expiry(max(
+    fn store_structure_orders(
+        &self,
+        cache: &mut Cache<MarketOrdersReq, MarketOrdersDetailedRep>,
+        raws: Expirable<Option<Vec<StructureOrder>>>,
+        market: &str,
+    ) {
+        let expiry: u64 = max(
             raws.expires_in,
             self.min_cache_time.structure_market_orders(),
-        ));
+        );
+        let had_snapshot: bool = cache.expiry() != 0;
+        let raws: Vec<StructureOrder> = match raws.into_inner() {
+            Some(raws) => raws,
+            None => {
+                cache.update_expiry(expiry);
+                self.record_history(cache);
+                return self.record_order_changes(None, cache);
+            },
+        };
+        let previous = cache.clear_and_update_expiry(expiry);
 
-        let mut reps: HashMap<(TypeId, bool), MarketOrdersRep> = HashMap::new();
-        for raw in raws.into_inner().into_iter() {
+        let mut reps: HashMap<(TypeId, bool), MarketOrdersDetailedRep> =
+            HashMap::new();
+        for raw in raws.into_iter() {
             let k = (raw.type_id, raw.is_buy_order);
             match reps.get_mut(&k) {
                 Some(r) => r
                     .market_orders
                     .push(raw.into_proto()),
                 None => reps
-                    .insert(k, MarketOrdersRep {
+                    .insert(k, MarketOrdersDetailedRep {
                         market_orders: vec![raw.into_proto()]
                     })
                     .map_or_else(|| (), |_| ()),
@@ -251,18 +1056,104 @@ impl Service {
             cache.insert(
                 MarketOrdersReq {
                     type_id: type_id,
-                    market: req.market.clone(),
+                    market: market.to_string(),
                     buy: is_buy_order,
                 },
                 rep,
             );
         }
+        self.record_history(cache);
+        self.record_order_changes(had_snapshot.then_some(&previous), cache);
+    }
 
-        match cache.get_forced(&req) {
-            Some(rep) => Ok(Response::new(rep.clone())),
-            None => Ok(Response::new(MarketOrdersRep {
-                market_orders: Vec::new(),
-            }))
+    async fn lock_adjusted_price_cache(
+        &self,
+    ) -> Result<(OwnedMutexGuard<Cache<AdjustedPriceReq, AdjustedPriceRep>>, bool), Status> {
+        self.lock_cache(
+            self.adjusted_price_cache.clone(),
+            |client| async move { client.get_adjusted_price().await },
+            |service, cache, raws| service.store_adjusted_prices(cache, raws),
+        )
+            .await
+    }
+
+    async fn lock_system_index_cache(
+        &self,
+    ) -> Result<(OwnedMutexGuard<Cache<SystemIndexReq, SystemIndexRep>>, bool), Status> {
+        self.lock_cache(
+            self.system_index_cache.clone(),
+            |client| async move { client.get_system_index().await },
+            |service, cache, raws| service.store_system_indices(cache, raws),
+        )
+            .await
+    }
+
+    fn store_adjusted_prices(
+        &self,
+        cache: &mut Cache<AdjustedPriceReq, AdjustedPriceRep>,
+        raws: Expirable<Option<Vec<AdjustedPrice>>>,
+    ) {
+        let expiry: u64 = max(
+            raws.expires_in,
+            self.min_cache_time.adjusted_price(),
+        );
+        let raws: Vec<AdjustedPrice> = match raws.into_inner() {
+            Some(raws) => raws,
+            None => return cache.update_expiry(expiry),
+        };
+        cache.clear_and_update_expiry(expiry);
+        for raw in raws.into_iter() {
+            cache.insert(
+                raw.clone().into_proto_req(),
+                raw.into_proto(),
+            );
+        }
+    }
+
+    // Raws is None if ESI has no history for the type
+    fn store_market_history(
+        &self,
+        cache: &mut Cache<(RegionId, TypeId), MarketHistoryRep>,
+        raws: Option<Expirable<Vec<MarketHistory>>>,
+        k: (RegionId, TypeId),
+    ) {
+        let raws: Expirable<Vec<MarketHistory>> = match raws {
+            Some(raws) => raws,
+            None => {
+                cache.clear_and_update_expiry(time::now() + MISSING_HISTORY_CACHE_TIME);
+                return;
+            },
+        };
+        cache.clear_and_update_expiry(raws.expires_in);
+        let raws: Vec<MarketHistory> = raws.into_inner();
+        cache.insert(k, MarketHistoryRep {
+            days: raws
+                .into_iter()
+                .map(|raw| raw.into_proto())
+                .collect(),
+            expires: 0,
+        });
+    }
+
+    fn store_system_indices(
+        &self,
+        cache: &mut Cache<SystemIndexReq, SystemIndexRep>,
+        raws: Expirable<Option<Vec<SystemIndex>>>,
+    ) {
+        let expiry: u64 = max(
+            raws.expires_in,
+            self.min_cache_time.system_index(),
+        );
+        let raws: Vec<SystemIndex> = match raws.into_inner() {
+            Some(raws) => raws,
+            None => return cache.update_expiry(expiry),
+        };
+        cache.clear_and_update_expiry(expiry);
+        for raw in raws.into_iter() {
+            cache.insert(
+                raw.clone().into_proto_req(),
+                raw.into_proto(),
+            );
         }
     }
 }
@@ -280,53 +1171,345 @@ impl WeveMarket for Service {
             req.market,
             req.buy,
         );
-        match self.markets.get(&req.market) {
-            Some((location_id, Either::Left(region_id))) => self
-                .station_orders(req, location_id, region_id)
-                .await,
-            Some((location_id, Either::Right(refresh_token))) => self
-                .structure_orders(req, location_id, refresh_token.as_deref())
-                .await,
-            None => Ok(Response::new(MarketOrdersRep{
-                market_orders: Vec::new(),
-            })),
-        }
+        self.orders(req)
+            .await
+            .map(|rep| rep.map(|rep| rep.summarize()))
     }
 
-    async fn adjusted_price(
+    async fn market_orders_detailed(
         &self,
-        request: Request<AdjustedPriceReq>,
-    ) -> Result<Response<AdjustedPriceRep>, Status> {
+        request: Request<MarketOrdersReq>,
+    ) -> Result<Response<MarketOrdersDetailedRep>, Status> {
         let req = request.into_inner();
         println!(
-            "Received AdjustedPriceReq: [{}]",
+            "Received MarketOrdersDetailedReq: [{}] [{}] [{}]",
             req.type_id,
+            req.market,
+            req.buy,
         );
-        let cache_ref = self.adjusted_price_cache.clone();
-        let mut cache = cache_ref.lock().await;
+        self.orders(req).await
+    }
 
-        if let Some(rep) = cache.get(&req) {
-            return Ok(Response::new(rep.clone()));
+    async fn market_stats(
+        &self,
+        request: Request<MarketStatsReq>,
+    ) -> Result<Response<MarketStatsRep>, Status> {
+        let req = request.into_inner();
+        println!(
+            "Received MarketStatsReq: [{}] [{}] [{:?}] [{}]",
+            req.type_id,
+            req.market,
+            req.side(),
+            req.percentile,
+        );
+        let percentile: f64 = match req.percentile {
+            p if p > 0.0 => p,
+            _ => stats::DEFAULT_PERCENTILE,
+        };
+
+        let mut rep: MarketStatsRep = MarketStatsRep::default();
+        let mut stale: bool = false;
+        for buy in sides(req.side()) {
+            let orders: Response<MarketOrdersDetailedRep> = self
+                .orders(MarketOrdersReq {
+                    type_id: req.type_id,
+                    market: req.market.clone(),
+                    buy: buy,
+                })
+                .await?;
+            stale |= is_stale(&orders);
+            let side_stats: MarketSideStats = stats::side_stats(
+                &orders.get_ref().market_orders,
+                buy,
+                percentile,
+            );
+            match buy {
+                true => rep.buy = Some(side_stats),
+                false => rep.sell = Some(side_stats),
+            }
+        }
+        if let (Some(buy), Some(sell)) = (&rep.buy, &rep.sell) {
+            rep.spread = stats::spread(buy, sell);
         }
+        Ok(respond(rep, stale))
+    }
+
+    async fn fill_price(
+        &self,
+        request: Request<FillPriceReq>,
+    ) -> Result<Response<FillPriceRep>, Status> {
+        let req = request.into_inner();
+        println!(
+            "Received FillPriceReq: [{}] [{}] [{}] [{}]",
+            req.type_id,
+            req.market,
+            req.buy,
+            req.quantity,
+        );
+        let location_id: LocationId = match self.markets.get(&req.market) {
+            Some((location_id, _)) => *location_id,
+            None => return self.not_found(
+                Error::MarketNotFound(req.market),
+                false,
+            ),
+        };
 
-        let raws: Expirable<Vec<AdjustedPrice>> = self
-            .esi_client
-            .get_adjusted_price()
+        let orders: Response<MarketOrdersDetailedRep> = self
+            .orders(MarketOrdersReq {
+                type_id: req.type_id,
+                market: req.market,
+                buy: req.buy,
+            })
+            .await?;
+        Ok(respond(
+            stats::fill(
+                &orders.get_ref().market_orders,
+                location_id,
+                req.buy,
+                req.quantity,
+            ),
+            is_stale(&orders),
+        ))
+    }
+
+    // Refreshes the book if it has expired, so the changes are at most as old
+    // as the book MarketOrders would return
+    async fn order_changes(
+        &self,
+        request: Request<MarketOrdersReq>,
+    ) -> Result<Response<OrderChangesRep>, Status> {
+        let req = request.into_inner();
+        println!(
+            "Received OrderChangesReq: [{}] [{}] [{}]",
+            req.type_id,
+            req.market,
+            req.buy,
+        );
+        let stale: bool = is_stale(&self.orders(req.clone()).await?);
+        let changes: OrderChangesRep = self.order_changes
+            .lock()
+            .unwrap()
+            .get(&req)
+            .cloned()
+            .unwrap_or_default();
+        Ok(respond(changes, stale))
+    }
+
+    async fn price_history(
+        &self,
+        request: Request<PriceHistoryReq>,
+    ) -> Result<Response<PriceHistoryRep>, Status> {
+        let req = request.into_inner();
+        println!(
+            "Received PriceHistoryReq: [{}] [{}] [{}] [{}] [{}] [{}]",
+            req.type_id,
+            req.market,
+            req.buy,
+            req.from,
+            req.to,
+            req.resolution,
+        );
+        let history: Arc<HistoryStore> = match &self.history {
+            Some(history) => history.clone(),
+            None => return Err(Error::HistoryDisabled.into()),
+        };
+        if self.markets.get(&req.market).is_none() {
+            return self.not_found(Error::MarketNotFound(req.market), false);
+        }
+        let to: u64 = match req.to {
+            0 => time::now(),
+            to => to,
+        };
+        let points: Vec<PricePoint> = tokio::task::spawn_blocking(move || {
+            history.query(
+                &req.market,
+                req.type_id,
+                req.buy,
+                req.from,
+                to,
+                req.resolution,
+            )
+        })
             .await
-            .unwrap();
-        cache.clear_and_update_expiry(max(
-            raws.expires_in,
-            self.min_cache_time.adjusted_price(),
-        ));
+            .expect("history queries do not panic")
+            .map_err(|e| Error::from(e))?;
+        Ok(Response::new(PriceHistoryRep {
+            points: points,
+        }))
+    }
 
-        for raw in raws.into_inner().into_iter() {
-            cache.insert(
-                raw.clone().into_proto_req(),
-                raw.into_proto(),
-            );
+    // Cached until ESI's copy expires, as ESI only updates it once a day
+    async fn market_history(
+        &self,
+        request: Request<MarketHistoryReq>,
+    ) -> Result<Response<MarketHistoryRep>, Status> {
+        let req = request.into_inner();
+        println!(
+            "Received MarketHistoryReq: [{}] [{}] [{}]",
+            req.type_id,
+            req.market,
+            req.region_id,
+        );
+        let region_id: RegionId = match self.markets.get(&req.market) {
+            _ if req.market.is_empty() && req.region_id != 0 => req.region_id,
+            Some((_, Either::Left(region_id))) => *region_id,
+            Some((_, Either::Right(_))) => {
+                return Err(Error::MarketHasNoRegion(req.market).into());
+            },
+            _ => return self.not_found(Error::MarketNotFound(req.market), false),
+        };
+        if req.type_id <= 0 {
+            return Err(Error::InvalidTypeId(req.type_id).into());
+        }
+        // Only regions with a configured station market are proxied, so that
+        // callers can't spend the ESI error budget on arbitrary ids
+        let configured: bool = self.markets
+            .values()
+            .any(|(_, either)| matches!(either, Either::Left(r) if *r == region_id));
+        if !configured {
+            return self.not_found(Error::RegionNotFound(region_id), false);
+        }
+
+        let k: (RegionId, TypeId) = (region_id, req.type_id);
+        let result = self
+            .lock_cache(
+                self.market_history_cache_ref(&k),
+                // Types ESI does not know are remembered as such, so that they
+                // don't reach ESI on every request either
+                move |client| async move {
+                    match client.get_market_history(&k.0, &k.1).await {
+                        Ok(raws) => Ok(Some(raws)),
+                        Err(EsiError::EsiStatusCode(status, _))
+                            if status.as_u16() == 404 => Ok(None),
+                        Err(e) => Err(e),
+                    }
+                },
+                move |service, cache, raws| service.store_market_history(
+                    cache,
+                    raws,
+                    k,
+                ),
+            )
+            .await;
+        let (cache, stale) = match result {
+            Ok(locked) => locked,
+            Err(e) => {
+                self.forget_market_history(&k);
+                return Err(e);
+            },
+        };
+
+        let mut rep: MarketHistoryRep = match cache.get_forced(&k) {
+            Some(rep) => rep.clone(),
+            None => return self.not_found(Error::TypeIdNotFound(k.1), stale),
+        };
+        rep.expires = cache.expiry();
+        Ok(respond(rep, stale))
+    }
+
+    type SubscribeMarketOrdersStream = MarketOrdersUpdateStream;
+
+    // Streams the current book, then every refresh of it. A slow consumer
+    // only ever receives the latest book, skipping any it fell behind on.
+    async fn subscribe_market_orders(
+        &self,
+        request: Request<MarketOrdersReq>,
+    ) -> Result<Response<MarketOrdersUpdateStream>, Status> {
+        let req = request.into_inner();
+        println!(
+            "Received SubscribeMarketOrdersReq: [{}] [{}] [{}]",
+            req.type_id,
+            req.market,
+            req.buy,
+        );
+        let cache_ref: MarketOrdersCache = match self.orders_cache_ref(&req) {
+            Some(cache_ref) => cache_ref,
+            None => return Err(Error::MarketNotFound(req.market).into()),
+        };
+
+        let rep: MarketOrdersDetailedRep = self
+            .orders(req.clone())
+            .await?
+            .into_inner();
+        let initial: MarketOrdersUpdate = MarketOrdersUpdate {
+            market_orders: rep.market_orders,
+            expires: cache_ref.lock().await.expiry(),
+        };
+        let receiver = self.subscribe(req, cache_ref, initial.clone());
+
+        let updates = stream::unfold(receiver, |mut receiver| async move {
+            receiver.changed().await.ok()?;
+            let update: MarketOrdersUpdate = receiver.borrow_and_update().clone();
+            Some((Ok(update), receiver))
+        });
+        Ok(Response::new(
+            stream::once(async move { Ok(initial) })
+                .chain(updates)
+                .boxed()
+        ))
+    }
+
+    // Items are resolved concurrently, so requests for stations sharing a
+    // region wait on the same cache and its single fetch
+    async fn batch_market_orders(
+        &self,
+        request: Request<BatchMarketOrdersReq>,
+    ) -> Result<Response<BatchMarketOrdersRep>, Status> {
+        let req = request.into_inner();
+        println!(
+            "Received BatchMarketOrdersReq: [{:?}] [{:?}] [{:?}]",
+            req.type_ids,
+            req.markets,
+            req.buy,
+        );
+        let sides: Vec<bool> = match req.buy.is_empty() {
+            true => vec![true, false],
+            false => req.buy,
+        };
+
+        let mut seen: HashSet<MarketOrdersReq> = HashSet::new();
+        let mut reqs: Vec<MarketOrdersReq> = Vec::new();
+        for market in req.markets.iter() {
+            for type_id in req.type_ids.iter() {
+                for buy in sides.iter() {
+                    let item_req: MarketOrdersReq = MarketOrdersReq {
+                        type_id: *type_id,
+                        market: market.clone(),
+                        buy: *buy,
+                    };
+                    if seen.insert(item_req.clone()) {
+                        reqs.push(item_req);
+                    }
+                }
+            }
         }
 
-        Ok(Response::new(cache.get_forced(&req).unwrap().clone()))
+        let items: Vec<BatchMarketOrdersItem> = join_all(reqs
+            .into_iter()
+            .map(|item_req| async move {
+                let result = self.orders(item_req.clone()).await;
+                batch_market_orders_item(item_req, result)
+            })
+        )
+            .await;
+        Ok(Response::new(BatchMarketOrdersRep { items: items }))
+    }
+
+    async fn adjusted_price(
+        &self,
+        request: Request<AdjustedPriceReq>,
+    ) -> Result<Response<AdjustedPriceRep>, Status> {
+        let req = request.into_inner();
+        println!(
+            "Received AdjustedPriceReq: [{}]",
+            req.type_id,
+        );
+        let (cache, stale) = self.lock_adjusted_price_cache().await?;
+
+        match cache.get_forced(&req) {
+            Some(rep) => Ok(respond(rep.clone(), stale)),
+            None => self.not_found(Error::TypeIdNotFound(req.type_id), stale),
+        }
     }
 
     async fn system_index(
@@ -338,30 +1521,272 @@ impl WeveMarket for Service {
             "Received SystemIndexReq: [{}]",
             req.system_id,
         );
-        let cache_ref = self.system_index_cache.clone();
-        let mut cache = cache_ref.lock().await;
+        let (cache, stale) = self.lock_system_index_cache().await?;
 
-        if let Some(rep) = cache.get(&req) {
-            return Ok(Response::new(rep.clone()));
+        match cache.get_forced(&req) {
+            Some(rep) => Ok(respond(rep.clone(), stale)),
+            None => self.not_found(Error::SystemIdNotFound(req.system_id), stale),
         }
+    }
 
-        let raws: Expirable<Vec<SystemIndex>> = self
-            .esi_client
-            .get_system_index()
-            .await
-            .unwrap();
-        cache.clear_and_update_expiry(max(
-            raws.expires_in,
-            self.min_cache_time.system_index(),
-        ));
-        for raw in raws.into_inner().into_iter() {
-            cache.insert(
-                raw.clone().into_proto_req(),
-                raw.into_proto(),
-            );
+    async fn adjusted_prices(
+        &self,
+        request: Request<AdjustedPricesReq>,
+    ) -> Result<Response<AdjustedPricesRep>, Status> {
+        let req = request.into_inner();
+        println!(
+            "Received AdjustedPricesReq: [{:?}]",
+            req.type_ids,
+        );
+        let (cache, stale) = self.lock_adjusted_price_cache().await?;
+
+        let prices: HashMap<TypeId, AdjustedPriceRep> = match req.type_ids.is_empty() {
+            true => cache
+                .iter()
+                .map(|(k, v)| (k.type_id, v.clone()))
+                .collect(),
+            false => req.type_ids
+                .into_iter()
+                .filter_map(|type_id| cache
+                    .get_forced(&AdjustedPriceReq { type_id: type_id })
+                    .map(|v| (type_id, v.clone()))
+                )
+                .collect(),
+        };
+        Ok(respond(
+            AdjustedPricesRep {
+                prices: prices,
+                expires: cache.expiry(),
+            },
+            stale,
+        ))
+    }
+
+    async fn system_indices(
+        &self,
+        request: Request<SystemIndicesReq>,
+    ) -> Result<Response<SystemIndicesRep>, Status> {
+        let req = request.into_inner();
+        println!(
+            "Received SystemIndicesReq: [{:?}]",
+            req.system_ids,
+        );
+        let (cache, stale) = self.lock_system_index_cache().await?;
+
+        let indices: HashMap<i32, SystemIndexRep> = match req.system_ids.is_empty() {
+            true => cache
+                .iter()
+                .map(|(k, v)| (k.system_id, v.clone()))
+                .collect(),
+            false => req.system_ids
+                .into_iter()
+                .filter_map(|system_id| cache
+                    .get_forced(&SystemIndexReq { system_id: system_id })
+                    .map(|v| (system_id, v.clone()))
+                )
+                .collect(),
+        };
+        Ok(respond(
+            SystemIndicesRep {
+                indices: indices,
+                expires: cache.expiry(),
+            },
+            stale,
+        ))
+    }
+
+    async fn structure_market_auth(
+        &self,
+        _: Request<StructureMarketAuthReq>,
+    ) -> Result<Response<StructureMarketAuthRep>, Status> {
+        println!("Received StructureMarketAuthReq");
+        let mut markets: Vec<StructureMarketAuth> = Vec::new();
+        for (market, (location_id, either)) in self.markets.iter() {
+            let result: Result<TokenInfo, String> = match either {
+                Either::Right(Some(refresh_token)) => self
+                    .esi_client
+                    .token_info(refresh_token)
+                    .await
+                    .map_err(|e| e.to_string()),
+                Either::Right(None) => Err(
+                    "no refresh token configured".to_string()
+                ),
+                Either::Left(_) => continue,
+            };
+            markets.push(structure_market_auth(market, location_id, result));
         }
+        markets.sort_by(|a, b| a.market.cmp(&b.market));
+        Ok(Response::new(StructureMarketAuthRep { markets: markets }))
+    }
+
+    async fn esi_status(
+        &self,
+        _: Request<EsiStatusReq>,
+    ) -> Result<Response<EsiStatusRep>, Status> {
+        println!("Received EsiStatusReq");
+        let state: GovernorState = self.esi_client.governor_state();
+        Ok(Response::new(EsiStatusRep {
+            error_limit_known: state.error_limit_remain.is_some(),
+            error_limit_remain: state.error_limit_remain.unwrap_or_default(),
+            error_limit_reset: state.error_limit_reset,
+            paused: state.paused,
+            throttled: state.throttled,
+            requests_in_flight: state.requests_in_flight as u32,
+            max_concurrent_requests: state.max_concurrent_requests as u32,
+            delayed_requests: state.delayed_requests,
+            bytes_saved: self.esi_client.bytes_saved(),
+        }))
+    }
+}
+
+// Compacts the history store now and then for as long as the service runs
+fn start_history_compaction(history: Arc<HistoryStore>) {
+    tokio::spawn(async move {
+        loop {
+            let store: Arc<HistoryStore> = history.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                store.compact(time::now())
+            }).await;
+            if let Ok(Err(e)) = result {
+                println!("Failed to compact history: {}", e);
+            }
+            sleep(Duration::from_secs(HISTORY_COMPACTION_INTERVAL)).await;
+        }
+    });
+}
 
-        Ok(Response::new(cache.get_forced(&req).unwrap().clone()))
+fn structure_market_auth(
+    market: &str,
+    location_id: &LocationId,
+    result: Result<TokenInfo, String>,
+) -> StructureMarketAuth {
+    match result {
+        Ok(info) => StructureMarketAuth {
+            market: market.to_string(),
+            location_id: *location_id,
+            authorized: true,
+            character_id: info.character_id,
+            character_name: info.character_name,
+            scopes: info.scopes,
+            expiry: info.expiry,
+            error: String::new(),
+        },
+        Err(e) => StructureMarketAuth {
+            market: market.to_string(),
+            location_id: *location_id,
+            error: e,
+            ..Default::default()
+        },
+    }
+}
+
+fn batch_market_orders_item(
+    req: MarketOrdersReq,
+    result: Result<Response<MarketOrdersDetailedRep>, Status>,
+) -> BatchMarketOrdersItem {
+    match result {
+        Ok(rep) => BatchMarketOrdersItem {
+            req: Some(req),
+            stale: is_stale(&rep),
+            market_orders: rep.into_inner().summarize().market_orders,
+            ..Default::default()
+        },
+        Err(status) => BatchMarketOrdersItem {
+            req: Some(req),
+            code: status.code() as i32,
+            error: status.message().to_string(),
+            ..Default::default()
+        },
+    }
+}
+
+// The sides of the book a request covers, as values of MarketOrdersReq::buy
+fn sides(side: MarketSide) -> Vec<bool> {
+    match side {
+        MarketSide::Both => vec![true, false],
+        MarketSide::Buy => vec![true],
+        MarketSide::Sell => vec![false],
+    }
+}
+
+// Returns a summary of every book in the cache
+fn history_summaries(
+    cache: &Cache<MarketOrdersReq, MarketOrdersDetailedRep>,
+) -> Vec<(MarketOrdersReq, MarketSideStats)> {
+    cache
+        .iter()
+        .map(|(req, rep)| (
+            req.clone(),
+            stats::side_stats(
+                &rep.market_orders,
+                req.buy,
+                stats::DEFAULT_PERCENTILE,
+            ),
+        ))
+        .collect()
+}
+
+// Seconds until just after a subscribed book expires. A book that has
+// already expired was served stale while it refreshes in the background, so
+// it is polled again after the retry delay rather than every second.
+fn subscription_delay(expires: u64) -> u64 {
+    match expires.saturating_sub(time::now()) {
+        0 => SUBSCRIPTION_RETRY_DELAY,
+        left => left + 1,
+    }
+}
+
+fn is_stale<T>(response: &Response<T>) -> bool {
+    response.metadata().get(STALE_METADATA_KEY).is_some()
+}
+
+// Whether a buy order with the range, placed the given number of jumps away
+// from a station in the same region, can be filled at the station. Jumps is
+// None if the order is further away than any numeric range.
+fn in_range(range: &str, jumps: Option<&u32>) -> bool {
+    match (range, jumps) {
+        ("region", _) => true,
+        ("station", _) => false,
+        ("solarsystem", Some(jumps)) => *jumps == 0,
+        (range, Some(jumps)) => matches!(
+            range.parse::<u32>(),
+            Ok(range) if *jumps <= range,
+        ),
+        (_, None) => false,
+    }
+}
+
+fn order_type(buy: bool) -> &'static str {
+    match buy {
+        true => "buy",
+        false => "sell",
+    }
+}
+
+fn respond<T>(rep: T, stale: bool) -> Response<T> {
+    let mut response: Response<T> = Response::new(rep);
+    if stale {
+        response.metadata_mut().insert(
+            STALE_METADATA_KEY,
+            MetadataValue::from_static("true"),
+        );
+    }
+    response
+}
+
+impl MarketOrdersDetailedRep {
+    // Drops everything but the quantity and price of each order
+    fn summarize(self) -> MarketOrdersRep {
+        MarketOrdersRep {
+            market_orders: self.market_orders
+                .into_iter()
+                .map(|o| MarketOrder {
+                    quantity: o.volume_remain,
+                    price: o.price,
+                    remote: o.remote,
+                })
+                .collect(),
+        }
     }
 }
 
@@ -0,0 +1,125 @@
+#![allow(clippy::redundant_field_names)]
+
+use crate::{
+    LocationId,
+    proto::{FillPriceRep, MarketOrderDetailed, MarketSideStats},
+};
+
+// Share of the volume, in percent, the percentile price is taken over when
+// the request leaves it unset
+pub const DEFAULT_PERCENTILE: f64 = 5.0;
+
+// Summarizes one side of a book. Prices are walked from the best order
+// outwards, highest first for buy orders and lowest first for sell orders.
+pub fn side_stats(
+    orders: &[MarketOrderDetailed],
+    buy: bool,
+    percentile: f64,
+) -> MarketSideStats {
+    let mut orders: Vec<(f64, i64)> = orders
+        .iter()
+        .map(|o| (o.price, o.volume_remain as i64))
+        .filter(|(_, volume)| *volume > 0)
+        .collect();
+    orders.sort_by(|a, b| match buy {
+        true => b.0.total_cmp(&a.0),
+        false => a.0.total_cmp(&b.0),
+    });
+
+    let volume: i64 = orders.iter().map(|(_, v)| v).sum();
+    if volume == 0 {
+        return MarketSideStats::default();
+    }
+
+    MarketSideStats {
+        order_count: orders.len() as u32,
+        volume: volume,
+        best: orders[0].0,
+        weighted_average: weighted_average(&orders, volume as f64),
+        median: price_at(&orders, (volume + 1) / 2),
+        percentile: weighted_average(
+            &orders,
+            (volume as f64 * percentile.clamp(0.0, 100.0) / 100.0).max(1.0),
+        ),
+    }
+}
+
+// Fills quantity units from the best price outwards, using only orders at
+// the market's location or remote buy orders that reach it. Orders whose
+// min_volume exceeds what is left to fill are skipped, as they can not take
+// a smaller transaction.
+pub fn fill(
+    orders: &[MarketOrderDetailed],
+    location_id: LocationId,
+    buy: bool,
+    quantity: i64,
+) -> FillPriceRep {
+    let mut orders: Vec<&MarketOrderDetailed> = orders
+        .iter()
+        .filter(|o| o.location_id == location_id || o.remote)
+        .filter(|o| o.volume_remain > 0)
+        .collect();
+    orders.sort_by(|a, b| match buy {
+        true => b.price.total_cmp(&a.price),
+        false => a.price.total_cmp(&b.price),
+    });
+
+    let mut rep: FillPriceRep = FillPriceRep {
+        unfilled: quantity.max(0),
+        ..Default::default()
+    };
+    for order in orders {
+        if rep.unfilled == 0 {
+            break;
+        }
+        // An order with less left than its min_volume takes the remainder
+        if order.min_volume.min(order.volume_remain) as i64 > rep.unfilled {
+            continue;
+        }
+        let taken: i64 = rep.unfilled.min(order.volume_remain as i64);
+        rep.total += order.price * taken as f64;
+        rep.filled += taken;
+        rep.unfilled -= taken;
+        rep.worst_price = order.price;
+        rep.orders_filled += 1;
+    }
+    if rep.filled > 0 {
+        rep.average_price = rep.total / rep.filled as f64;
+    }
+    rep
+}
+
+// Ask minus bid, or 0 if either side is empty
+pub fn spread(buy: &MarketSideStats, sell: &MarketSideStats) -> f64 {
+    match buy.order_count > 0 && sell.order_count > 0 {
+        true => sell.best - buy.best,
+        false => 0.0,
+    }
+}
+
+// Average price paid for the first `units` of the sorted orders
+fn weighted_average(orders: &[(f64, i64)], units: f64) -> f64 {
+    let mut remaining: f64 = units;
+    let mut total: f64 = 0.0;
+    for (price, volume) in orders {
+        let taken: f64 = remaining.min(*volume as f64);
+        total += price * taken;
+        remaining -= taken;
+        if remaining <= 0.0 {
+            break;
+        }
+    }
+    total / (units - remaining)
+}
+
+// Price of the order holding the nth unit of the sorted orders
+fn price_at(orders: &[(f64, i64)], unit: i64) -> f64 {
+    let mut seen: i64 = 0;
+    for (price, volume) in orders {
+        seen += volume;
+        if seen >= unit {
+            return *price;
+        }
+    }
+    orders[orders.len() - 1].0
+}
@@ -0,0 +1,72 @@
+use crate::RefreshToken;
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Mutex,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+};
+
+// Remembers the newest refresh token SSO has handed out for each refresh
+// token in the configuration, so rotated tokens survive restarts.
+pub trait TokenStore: Send + Sync {
+    // Returns a map of configured refresh token to its newest rotation
+    fn load(&self) -> io::Result<HashMap<RefreshToken, RefreshToken>>;
+
+    fn save(&self, configured: &str, current: &str) -> io::Result<()>;
+}
+
+// Keeps the map as a JSON object in a single file
+pub struct FileTokenStore {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileTokenStore {
+    pub fn new(path: impl Into<PathBuf>) -> FileTokenStore {
+        FileTokenStore {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn read(&self) -> io::Result<HashMap<RefreshToken, RefreshToken>> {
+        match fs::read(&self.path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self) -> io::Result<HashMap<RefreshToken, RefreshToken>> {
+        let _guard = self.lock.lock().unwrap();
+        self.read()
+    }
+
+    fn save(&self, configured: &str, current: &str) -> io::Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let mut tokens = self.read()?;
+        tokens.insert(configured.to_string(), current.to_string());
+
+        // Write to a sibling file first so a crash never leaves a torn file
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        // The tokens grant market access, so only the owner may read them; a
+        // leftover file is replaced as it may have been created differently
+        match fs::remove_file(&tmp_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        let mut options: OpenOptions = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file: fs::File = options.open(&tmp_path)?;
+        file.write_all(&serde_json::to_vec_pretty(&tokens)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
@@ -0,0 +1,515 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{TimeZone, Utc};
+use hyper::{
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+    header::HeaderMap,
+    service::{make_service_fn, service_fn},
+};
+use jsonwebtoken::{EncodingKey, Header, Algorithm};
+use serde_json::{json, Value};
+
+const SSO_KEY: &[u8] = include_bytes!("../fixtures/sso_key.pem");
+const JWKS: &str = include_str!("../fixtures/jwks.json");
+const STATION_ORDERS: &str = include_str!("../fixtures/station_orders.json");
+const STRUCTURE_ORDERS: &str = include_str!("../fixtures/structure_orders.json");
+const ADJUSTED_PRICES: &str = include_str!("../fixtures/adjusted_prices.json");
+const SYSTEM_INDICES: &str = include_str!("../fixtures/system_indices.json");
+const MARKET_HISTORY: &str = include_str!("../fixtures/market_history.json");
+
+pub const CHARACTER_ID: i64 = 2112625428;
+pub const CHARACTER_NAME: &str = "Weve Trader";
+pub const STRUCTURE_SCOPE: &str = "esi-markets.structure_markets.v1";
+
+// Status and extra headers of an injected error response
+type Failure = (u16, Vec<(String, String)>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Route {
+    Token,
+    Jwks,
+    StationOrders,
+    StructureOrders,
+    AdjustedPrices,
+    SystemIndices,
+    MarketHistory,
+}
+
+// A stand-in for ESI and EVE SSO serving the fixtures with the headers
+// the real thing sends: expires shared by every page of a snapshot,
+// x-pages, and etag.
+pub struct MockEsi {
+    pub url: String,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    url: String,
+    client_id: String,
+    station_orders: Vec<Value>,
+    structure_orders: Vec<Value>,
+    adjusted_prices: Value,
+    system_indices: Value,
+    page_size: usize,
+    expires_in: i64,
+    snapshot_expires: i64,
+    failures: HashMap<Route, VecDeque<Failure>>,
+    cut_bodies: HashMap<Route, usize>,
+    requests: HashMap<Route, usize>,
+    not_modified: HashMap<Route, usize>,
+    error_limit: Option<(u32, u64)>,
+    refresh_tokens: HashSet<String>,
+    access_tokens: HashSet<String>,
+    rotate_refresh_tokens: bool,
+    issued_refresh_tokens: usize,
+    token_expires_in: u64,
+    scopes: Vec<String>,
+}
+
+impl MockEsi {
+    pub async fn start(client_id: &str, refresh_token: &str) -> MockEsi {
+        let state = Arc::new(Mutex::new(State {
+            url: String::new(),
+            client_id: client_id.to_string(),
+            station_orders: serde_json::from_str(STATION_ORDERS).unwrap(),
+            structure_orders: serde_json::from_str(STRUCTURE_ORDERS).unwrap(),
+            adjusted_prices: serde_json::from_str(ADJUSTED_PRICES).unwrap(),
+            system_indices: serde_json::from_str(SYSTEM_INDICES).unwrap(),
+            page_size: 1000,
+            expires_in: 300,
+            snapshot_expires: 0,
+            failures: HashMap::new(),
+            cut_bodies: HashMap::new(),
+            requests: HashMap::new(),
+            not_modified: HashMap::new(),
+            error_limit: None,
+            refresh_tokens: HashSet::from([refresh_token.to_string()]),
+            access_tokens: HashSet::new(),
+            rotate_refresh_tokens: false,
+            issued_refresh_tokens: 0,
+            token_expires_in: 1199,
+            scopes: vec![STRUCTURE_SCOPE.to_string()],
+        }));
+
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let state = state.clone();
+                    async move {
+                        let sso: bool = req.uri().path().contains("oauth");
+                        let mut response = handle(state.clone(), req).await;
+                        if !sso {
+                            state
+                                .lock()
+                                .unwrap()
+                                .add_error_limit(response.headers_mut());
+                        }
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        state.lock().unwrap().url = url.clone();
+        tokio::spawn(server);
+
+        MockEsi {
+            url: url,
+            state: state,
+        }
+    }
+
+    // Number of requests, HEADs included, the route has received
+    pub fn requests(&self, route: Route) -> usize {
+        *self.state.lock().unwrap().requests.get(&route).unwrap_or(&0)
+    }
+
+    // Requests answered with 304 Not Modified
+    pub fn not_modified(&self, route: Route) -> usize {
+        *self.state.lock().unwrap().not_modified.get(&route).unwrap_or(&0)
+    }
+
+    // Reports the error budget and the seconds until it resets on every ESI
+    // response
+    pub fn set_error_limit(&self, remain: u32, reset: u64) {
+        self.state.lock().unwrap().error_limit = Some((remain, reset));
+    }
+
+    // Makes the next `times` requests to the route fail with the status
+    pub fn fail(&self, route: Route, status: u16, times: usize) {
+        self.fail_with_headers(route, status, times, &[]);
+    }
+
+    pub fn fail_with_headers(
+        &self,
+        route: Route,
+        status: u16,
+        times: usize,
+        headers: &[(&str, &str)],
+    ) {
+        let headers: Vec<(String, String)> = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut state = self.state.lock().unwrap();
+        let failures = state.failures.entry(route).or_default();
+        for _ in 0..times {
+            failures.push_back((status, headers.clone()));
+        }
+    }
+
+    // Makes the next `times` successful GETs of the route break off halfway
+    // through the body
+    pub fn cut_bodies(&self, route: Route, times: usize) {
+        self.state.lock().unwrap().cut_bodies.insert(route, times);
+    }
+
+    // Seconds each new snapshot stays valid for, ending the current one
+    pub fn set_expires_in(&self, expires_in: i64) {
+        let mut state = self.state.lock().unwrap();
+        state.expires_in = expires_in;
+        state.snapshot_expires = 0;
+    }
+
+    pub fn set_page_size(&self, page_size: usize) {
+        self.state.lock().unwrap().page_size = page_size;
+    }
+
+    pub fn set_token_expires_in(&self, token_expires_in: u64) {
+        self.state.lock().unwrap().token_expires_in = token_expires_in;
+    }
+
+    pub fn set_scopes(&self, scopes: &[&str]) {
+        self.state.lock().unwrap().scopes = scopes
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+    }
+
+    // Issues a new refresh token on every refresh, revoking the old one
+    pub fn rotate_refresh_tokens(&self, rotate: bool) {
+        self.state.lock().unwrap().rotate_refresh_tokens = rotate;
+    }
+
+    pub fn update_station_orders(&self, f: impl FnOnce(&mut Vec<Value>)) {
+        f(&mut self.state.lock().unwrap().station_orders);
+    }
+}
+
+async fn handle(
+    state: Arc<Mutex<State>>,
+    req: Request<Body>,
+) -> Response<Body> {
+    let method: Method = req.method().clone();
+    let path: String = req.uri().path().to_string();
+    let query: HashMap<String, String> = parse_pairs(
+        req.uri().query().unwrap_or(""),
+    );
+    let authorization: Option<String> = req
+        .headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    let if_none_match: Option<String> = req
+        .headers()
+        .get("if-none-match")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    let route: Route = match segments.as_slice() {
+        ["v2", "oauth", "token"] => Route::Token,
+        ["oauth", "jwks"] => Route::Jwks,
+        ["markets", "prices"] => Route::AdjustedPrices,
+        ["industry", "systems"] => Route::SystemIndices,
+        ["markets", "structures", _] => Route::StructureOrders,
+        ["markets", _, "orders"] => Route::StationOrders,
+        ["markets", _, "history"] => Route::MarketHistory,
+        _ => return error(StatusCode::NOT_FOUND, "Not found", &[]),
+    };
+
+    let mut state = state.lock().unwrap();
+    *state.requests.entry(route).or_default() += 1;
+    if let Some((status, headers)) = state
+        .failures
+        .get_mut(&route)
+        .and_then(|f| f.pop_front())
+    {
+        return error(
+            StatusCode::from_u16(status).unwrap(),
+            "Injected failure",
+            &headers,
+        );
+    }
+
+    match route {
+        Route::Token => return state.token(&method, &body),
+        Route::Jwks => return json_response(StatusCode::OK, JWKS.to_string()),
+        _ => (),
+    }
+
+    let items: Vec<Value> = match route {
+        Route::StationOrders => {
+            let type_id: Option<i64> = query
+                .get("type_id")
+                .and_then(|t| t.parse().ok());
+            let order_type: &str = query
+                .get("order_type")
+                .map_or("all", |t| t.as_str());
+            state.station_orders
+                .iter()
+                .filter(|o| match type_id {
+                    Some(t) => o["type_id"] == t,
+                    None => true,
+                })
+                .filter(|o| match order_type {
+                    "buy" => o["is_buy_order"] == true,
+                    "sell" => o["is_buy_order"] == false,
+                    _ => true,
+                })
+                .cloned()
+                .collect()
+        },
+        Route::StructureOrders => {
+            let authorized: bool = matches!(
+                authorization
+                    .as_deref()
+                    .and_then(|a| a.strip_prefix("Bearer ")),
+                Some(t) if state.access_tokens.contains(t),
+            );
+            if !authorized {
+                return error(
+                    StatusCode::UNAUTHORIZED,
+                    "authentication failure",
+                    &[],
+                );
+            }
+            state.structure_orders.clone()
+        },
+        Route::AdjustedPrices => {
+            state.adjusted_prices.as_array().unwrap().clone()
+        },
+        Route::SystemIndices => {
+            state.system_indices.as_array().unwrap().clone()
+        },
+        Route::MarketHistory => {
+            let history: Value = serde_json::from_str(MARKET_HISTORY).unwrap();
+            let type_id: &str = query.get("type_id").map_or("", |t| t.as_str());
+            match history[type_id].as_array() {
+                Some(days) => days.clone(),
+                None => return error(StatusCode::NOT_FOUND, "Type not found!", &[]),
+            }
+        },
+        _ => unreachable!(),
+    };
+
+    let paginated: bool = matches!(
+        route,
+        Route::StationOrders | Route::StructureOrders,
+    );
+    let page_size: usize = match paginated {
+        true => state.page_size,
+        false => items.len().max(1),
+    };
+    let pages: usize = items.len().div_ceil(page_size).max(1);
+    let page: usize = query
+        .get("page")
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(1);
+    if page > pages {
+        return error(StatusCode::NOT_FOUND, "Requested page does not exist!", &[]);
+    }
+    let page_items: Vec<Value> = items
+        .into_iter()
+        .skip((page - 1) * page_size)
+        .take(page_size)
+        .collect();
+
+    let body: String = Value::Array(page_items).to_string();
+    let etag: String = format!("\"{:x}\"", {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        hasher.finish()
+    });
+    let expires: String = Utc
+        .timestamp_opt(state.snapshot_expires(), 0)
+        .unwrap()
+        .to_rfc2822()
+        .replace("+0000", "GMT");
+
+    let mut response = Response::builder()
+        .header("content-type", "application/json; charset=UTF-8")
+        .header("expires", expires)
+        .header("etag", etag.clone());
+    if paginated {
+        response = response.header("x-pages", pages.to_string());
+    }
+    if if_none_match.as_deref() == Some(etag.as_str()) {
+        *state.not_modified.entry(route).or_default() += 1;
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+    }
+    let cut: bool = match state.cut_bodies.get_mut(&route) {
+        Some(times) if *times > 0 && method == Method::GET => {
+            *times -= 1;
+            true
+        },
+        _ => false,
+    };
+    if cut {
+        // Aborted only once the headers and half of the body are out
+        let (mut sender, cut_body) = Body::channel();
+        let half: String = body[..body.len() / 2].to_string();
+        tokio::spawn(async move {
+            let _ = sender.send_data(half.into()).await;
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            sender.abort();
+        });
+        return response
+            .status(StatusCode::OK)
+            .header("content-length", body.len())
+            .body(cut_body)
+            .unwrap();
+    }
+    response
+        .status(StatusCode::OK)
+        .body(match method {
+            Method::HEAD => Body::empty(),
+            _ => Body::from(body),
+        })
+        .unwrap()
+}
+
+impl State {
+    fn add_error_limit(&self, headers: &mut HeaderMap) {
+        if let Some((remain, reset)) = self.error_limit {
+            headers.insert("x-esi-error-limit-remain", remain.into());
+            headers.insert("x-esi-error-limit-reset", reset.into());
+        }
+    }
+
+    // Every page requested before the snapshot expires shares its expiry,
+    // like ESI's own cache.
+    fn snapshot_expires(&mut self) -> i64 {
+        let now: i64 = Utc::now().timestamp();
+        if now >= self.snapshot_expires {
+            self.snapshot_expires = now + self.expires_in;
+        }
+        self.snapshot_expires
+    }
+
+    fn token(&mut self, method: &Method, body: &[u8]) -> Response<Body> {
+        if method != Method::POST {
+            return error(StatusCode::METHOD_NOT_ALLOWED, "POST only", &[]);
+        }
+        let form: HashMap<String, String> = parse_pairs(
+            std::str::from_utf8(body).unwrap_or(""),
+        );
+        let refresh_token: &str = match form.get("refresh_token") {
+            Some(t) if self.refresh_tokens.contains(t) => t,
+            _ => return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({
+                    "error": "invalid_grant",
+                    "error_description": "Invalid refresh token.",
+                }).to_string(),
+            ),
+        };
+
+        let refresh_token: String = match self.rotate_refresh_tokens {
+            true => {
+                self.refresh_tokens.remove(refresh_token);
+                self.issued_refresh_tokens += 1;
+                let rotated = format!("rotated-{}", self.issued_refresh_tokens);
+                self.refresh_tokens.insert(rotated.clone());
+                rotated
+            },
+            false => refresh_token.to_string(),
+        };
+
+        let now: u64 = Utc::now().timestamp() as u64;
+        let mut header: Header = Header::new(Algorithm::RS256);
+        header.kid = Some("JWT-Signature-Key".to_string());
+        let access_token: String = jsonwebtoken::encode(
+            &header,
+            &json!({
+                "scp": self.scopes,
+                "jti": format!("{}-{}", now, self.access_tokens.len()),
+                "kid": "JWT-Signature-Key",
+                "sub": format!("CHARACTER:EVE:{}", CHARACTER_ID),
+                "azp": self.client_id,
+                "tenant": "tranquility",
+                "tier": "live",
+                "region": "world",
+                "aud": [self.client_id, "EVE Online"],
+                "name": CHARACTER_NAME,
+                "owner": "owner-hash",
+                "exp": now + self.token_expires_in,
+                "iat": now,
+                "iss": self.url,
+            }),
+            &EncodingKey::from_rsa_pem(SSO_KEY).unwrap(),
+        )
+            .unwrap();
+        self.access_tokens.insert(access_token.clone());
+
+        json_response(StatusCode::OK, json!({
+            "access_token": access_token,
+            "expires_in": self.token_expires_in,
+            "token_type": "Bearer",
+            "refresh_token": refresh_token,
+        }).to_string())
+    }
+}
+
+fn parse_pairs(s: &str) -> HashMap<String, String> {
+    s.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn json_response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json; charset=UTF-8")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn error(
+    status: StatusCode,
+    message: &str,
+    headers: &[(String, String)],
+) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(status)
+        .header("content-type", "application/json; charset=UTF-8");
+    for (k, v) in headers {
+        response = response.header(k.as_str(), v.as_str());
+    }
+    response
+        .body(Body::from(json!({ "error": message }).to_string()))
+        .unwrap()
+}
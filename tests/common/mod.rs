@@ -0,0 +1,183 @@
+#![allow(dead_code)]
+
+pub mod mock_esi;
+
+use mock_esi::MockEsi;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use either::Either;
+use tonic::transport::Channel;
+use weve_market::{
+    RefreshToken,
+    config::{
+        BuyRange,
+        Endpoints,
+        ErrorLimit,
+        Markets,
+        MinCacheDuration,
+        Prefetch,
+        Retry,
+    },
+    esi_client::Client,
+    history::HistoryStore,
+    proto::weve_market_client::WeveMarketClient,
+    service::Service,
+    token_store::TokenStore,
+};
+
+pub const CLIENT_ID: &str = "mock-client-id";
+pub const REFRESH_TOKEN: &str = "configured-refresh-token";
+
+pub const JITA: &str = "jita";
+pub const JITA_LOCATION_ID: i64 = 60003760;
+pub const JITA_NEIGHBOUR: &str = "jita_neighbour";
+pub const JITA_NEIGHBOUR_LOCATION_ID: i64 = 60003766;
+pub const THE_FORGE: i32 = 10000002;
+pub const KEEPSTAR: &str = "keepstar";
+pub const KEEPSTAR_LOCATION_ID: i64 = 1022734985679;
+
+pub const TRITANIUM: i32 = 34;
+pub const PYERITE: i32 = 35;
+
+pub struct Options {
+    pub max_staleness: u64,
+    pub strict: bool,
+    pub token_refresh_margin: u64,
+    pub error_limit: ErrorLimit,
+    pub retry: Retry,
+    pub buy_range: Option<BuyRange>,
+    pub bulk_regions: Vec<i32>,
+    pub history: Option<HistoryStore>,
+    pub prefetch: Prefetch,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_staleness: 0,
+            strict: true,
+            token_refresh_margin: 60,
+            error_limit: ErrorLimit::default(),
+            retry: Retry {
+                attempts: 3,
+                base_delay: 10,
+                max_delay: 100,
+            },
+            buy_range: None,
+            bulk_regions: Vec::new(),
+            history: None,
+            prefetch: Prefetch::default(),
+        }
+    }
+}
+
+pub struct Harness {
+    pub esi: MockEsi,
+    pub client: WeveMarketClient<Channel>,
+    pub tokens: MemoryTokenStore,
+}
+
+// Starts a mock ESI and a Service pointed at it, with two stations in The
+// Forge and one structure, and returns a connected gRPC client.
+pub async fn start(options: Options) -> Harness {
+    let esi: MockEsi = MockEsi::start(CLIENT_ID, REFRESH_TOKEN).await;
+    let tokens: MemoryTokenStore = MemoryTokenStore::default();
+
+    let mut markets: Markets = Markets::with_capacity(3);
+    markets.insert(
+        JITA.to_string(),
+        (JITA_LOCATION_ID, Either::Left(THE_FORGE)),
+    );
+    markets.insert(
+        JITA_NEIGHBOUR.to_string(),
+        (JITA_NEIGHBOUR_LOCATION_ID, Either::Left(THE_FORGE)),
+    );
+    markets.insert(
+        KEEPSTAR.to_string(),
+        (KEEPSTAR_LOCATION_ID, Either::Right(Some(REFRESH_TOKEN.to_string()))),
+    );
+
+    let esi_client: Client = Client::new(
+        "weve_market integration tests",
+        CLIENT_ID,
+        "mock-client-secret",
+        &markets.refresh_tokens(),
+        Some(Duration::from_secs(10)),
+        options.token_refresh_margin,
+        Box::new(tokens.clone()),
+        None,
+        Endpoints::new(&esi.url, &esi.url, "tranquility"),
+        options.error_limit,
+        options.retry,
+    );
+
+    let address: SocketAddr = free_address();
+    let service: Service = Service::new(
+        esi_client,
+        markets,
+        MinCacheDuration::default(),
+        options.max_staleness,
+        options.prefetch,
+        options.buy_range,
+        options.bulk_regions.into_iter().collect(),
+        options.history,
+        options.strict,
+        address,
+    );
+    tokio::spawn(service.serve());
+
+    Harness {
+        esi: esi,
+        client: connect(address).await,
+        tokens: tokens,
+    }
+}
+
+fn free_address() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+async fn connect(address: SocketAddr) -> WeveMarketClient<Channel> {
+    for _ in 0..100 {
+        match WeveMarketClient::connect(format!("http://{}", address)).await {
+            Ok(client) => return client,
+            Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+        }
+    }
+    panic!("service did not start listening on {}", address);
+}
+
+#[derive(Default, Clone)]
+pub struct MemoryTokenStore {
+    tokens: Arc<Mutex<HashMap<RefreshToken, RefreshToken>>>,
+}
+
+impl MemoryTokenStore {
+    pub fn get(&self, configured: &str) -> Option<RefreshToken> {
+        self.tokens.lock().unwrap().get(configured).cloned()
+    }
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn load(&self) -> io::Result<HashMap<RefreshToken, RefreshToken>> {
+        Ok(self.tokens.lock().unwrap().clone())
+    }
+
+    fn save(&self, configured: &str, current: &str) -> io::Result<()> {
+        self.tokens
+            .lock()
+            .unwrap()
+            .insert(configured.to_string(), current.to_string());
+        Ok(())
+    }
+}
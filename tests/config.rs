@@ -0,0 +1,152 @@
+use std::path::PathBuf;
+
+use weve_market::env::service_from_config;
+
+const VALID: &str = r#"
+service_address = "127.0.0.1:50051"
+
+[esi]
+user_agent = "weve_market config tests"
+client_id = "mock-client-id"
+client_secret = "mock-client-secret"
+
+[cache]
+station_market_orders = 300
+structure_market_orders = 300
+adjusted_price = 3600
+system_index = 3600
+
+[station_markets.jita]
+location_id = 60003760
+region_id = 10000002
+"#;
+
+// Writes the config to a file of its own in the temp directory
+fn write_config(name: &str, contents: &str) -> PathBuf {
+    let path: PathBuf = std::env::temp_dir().join(format!(
+        "weve_market_{}_{}",
+        std::process::id(),
+        name,
+    ));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn config_error(name: &str, contents: &str) -> String {
+    let path: PathBuf = write_config(name, contents);
+    let result = service_from_config(path.to_str());
+    std::fs::remove_file(&path).unwrap();
+    match result {
+        Ok(_) => panic!("{} was accepted", name),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[test]
+fn config_file_errors_name_the_bad_field() {
+    let wrong_type: String = config_error(
+        "wrong_type.toml",
+        &VALID.replace("= 300", "= \"soon\""),
+    );
+    let unknown: String = config_error(
+        "unknown.yaml",
+        "esi:\n  client_secert: mock-client-secret\n",
+    );
+    let format: String = config_error("format.ini", VALID);
+
+    assert!(wrong_type.contains("station_market_orders"), "{}", wrong_type);
+    assert!(unknown.contains("client_secert"), "{}", unknown);
+    assert!(format.contains(".toml"), "{}", format);
+}
+
+// Environment variables are shared by every test in the binary, so
+// everything that reads them runs in this one test
+#[test]
+fn env_vars_override_config_file_keys() {
+    let path: PathBuf = write_config("valid.toml", VALID);
+    assert!(service_from_config(path.to_str()).is_ok());
+    let example: String = std::fs::read_to_string(
+        concat!(env!("CARGO_MANIFEST_DIR"), "/config.example.toml"),
+    )
+        .unwrap();
+    let example: PathBuf = write_config(
+        "example.toml",
+        &example.replace("history.sqlite", ":memory:"),
+    );
+    assert!(service_from_config(example.to_str()).is_ok());
+    std::fs::remove_file(&example).unwrap();
+
+    let missing: String = config_error(
+        "missing.toml",
+        &VALID.replace("client_id = \"mock-client-id\"", ""),
+    );
+    let duplicate: String = config_error(
+        "duplicate.toml",
+        &format!("{}\n[structure_markets.jita]\nlocation_id = 1\n", VALID),
+    );
+    let limits: String = config_error(
+        "limits.toml",
+        &VALID.replace("[esi]", "[esi]\nerror_limit_pause = 60"),
+    );
+    let jwks: String = config_error(
+        "jwks.toml",
+        &VALID.replace("[esi]", "[esi]\nsso_jwks_path = \"/nonexistent/jwks.json\""),
+    );
+    let user_agent: String = config_error(
+        "user_agent.toml",
+        &VALID.replace("config tests", "config\\ntests"),
+    );
+    let esi_url: String = config_error(
+        "esi_url.toml",
+        &VALID.replace("[esi]", "[esi]\nurl = \"esi.example.com\""),
+    );
+    let unreadable = match service_from_config(Some("/nonexistent/weve_market.toml")) {
+        Ok(_) => panic!("a missing config file was accepted"),
+        Err(e) => e.to_string(),
+    };
+
+    std::env::set_var("WM_SSO_URL", "login.example.com/v2");
+    let sso_url = service_from_config(path.to_str());
+    std::env::remove_var("WM_SSO_URL");
+    std::env::set_var("WM_SERVICE_ADDRESS", "nowhere");
+    let overridden = service_from_config(path.to_str());
+    let invalid: PathBuf = write_config(
+        "invalid.toml",
+        &VALID.replace("service_address = \"127.0.0.1:50051\"", ""),
+    );
+    std::env::set_var("WM_SERVICE_ADDRESS", "127.0.0.1:50052");
+    let fixed = service_from_config(invalid.to_str());
+    std::env::remove_var("WM_SERVICE_ADDRESS");
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&invalid).unwrap();
+
+    assert!(missing.contains("esi.client_id (WM_CLIENT_ID)"), "{}", missing);
+    assert!(duplicate.contains("structure_markets.jita"), "{}", duplicate);
+    assert!(limits.contains("esi.error_limit_pause"), "{}", limits);
+    assert!(
+        jwks.contains("esi.sso_jwks_path (WM_SSO_JWKS_PATH)")
+            && jwks.contains("/nonexistent/jwks.json"),
+        "{}",
+        jwks,
+    );
+    assert!(user_agent.contains("esi.user_agent (WM_USER_AGENT)"), "{}", user_agent);
+    assert!(esi_url.contains("esi.url (WM_ESI_URL)"), "{}", esi_url);
+    match sso_url {
+        Ok(_) => panic!("invalid WM_SSO_URL was accepted"),
+        Err(e) => assert!(
+            e.to_string().contains("esi.sso_url (WM_SSO_URL)"),
+            "{}",
+            e,
+        ),
+    }
+    assert!(unreadable.contains("/nonexistent/weve_market.toml"), "{}", unreadable);
+    match overridden {
+        Ok(_) => panic!("invalid WM_SERVICE_ADDRESS was accepted"),
+        Err(e) => assert!(
+            e.to_string().contains("WM_SERVICE_ADDRESS"),
+            "{}",
+            e,
+        ),
+    }
+    assert!(fixed.is_ok());
+}
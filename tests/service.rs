@@ -0,0 +1,1361 @@
+#![allow(clippy::redundant_field_names)]
+
+mod common;
+
+use common::{
+    *,
+    mock_esi::{Route, CHARACTER_ID, CHARACTER_NAME, STRUCTURE_SCOPE},
+};
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use tonic::{Code, Response};
+use weve_market::{
+    config::{BuyRange, HistoryRetention, Prefetch, Retry},
+    history::HistoryStore,
+    proto::*,
+};
+
+fn market_orders_req(market: &str, type_id: i32, buy: bool) -> MarketOrdersReq {
+    MarketOrdersReq {
+        type_id: type_id,
+        market: market.to_string(),
+        buy: buy,
+    }
+}
+
+fn prices(rep: &MarketOrdersRep) -> Vec<f64> {
+    let mut prices: Vec<f64> = rep.market_orders
+        .iter()
+        .map(|o| o.price)
+        .collect();
+    prices.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    prices
+}
+
+fn is_stale<T>(response: &Response<T>) -> bool {
+    response.metadata().get("stale").is_some()
+}
+
+#[tokio::test]
+async fn station_orders_walk_every_page() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.set_page_size(2);
+
+    let rep = harness.client
+        .market_orders(market_orders_req(JITA, TRITANIUM, false))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(
+        prices(&rep),
+        vec![5.01, 5.02, 5.05, 5.10, 5.20, 5.50, 6.00],
+    );
+    // One HEAD, then five pages of two sell orders each
+    assert_eq!(harness.esi.requests(Route::StationOrders), 6);
+}
+
+#[tokio::test]
+async fn station_orders_are_cached_for_the_whole_region() {
+    let mut harness = start(Options::default()).await;
+
+    let jita = harness.client
+        .market_orders(market_orders_req(JITA, TRITANIUM, true))
+        .await
+        .unwrap()
+        .into_inner();
+    let requests = harness.esi.requests(Route::StationOrders);
+
+    let jita_again = harness.client
+        .market_orders(market_orders_req(JITA, TRITANIUM, true))
+        .await
+        .unwrap()
+        .into_inner();
+    let neighbour = harness.client
+        .market_orders(market_orders_req(JITA_NEIGHBOUR, TRITANIUM, true))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(prices(&jita), vec![4.50, 4.85, 4.90]);
+    assert_eq!(jita, jita_again);
+    assert!(neighbour.market_orders.is_empty());
+    assert_eq!(harness.esi.requests(Route::StationOrders), requests);
+
+    let neighbour = harness.client
+        .market_orders(market_orders_req(JITA_NEIGHBOUR, TRITANIUM, false))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(prices(&neighbour), vec![5.00, 5.03]);
+}
+
+#[tokio::test]
+async fn station_orders_are_refetched_after_expiry() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.set_expires_in(1);
+
+    let before = harness.client
+        .market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap()
+        .into_inner();
+    let requests = harness.esi.requests(Route::StationOrders);
+
+    harness.esi.update_station_orders(|orders| {
+        orders.retain(|o| o["price"] != 7.5);
+    });
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+
+    let after = harness.client
+        .market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap();
+
+    assert!(!is_stale(&after));
+    assert_eq!(prices(&before), vec![7.5, 7.6]);
+    assert_eq!(prices(after.get_ref()), vec![7.6]);
+    assert!(harness.esi.requests(Route::StationOrders) > requests);
+}
+
+#[tokio::test]
+async fn unchanged_orders_are_revalidated_with_etags() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.set_expires_in(1);
+    harness.esi.set_page_size(1);
+
+    let before = harness.client
+        .market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(harness.esi.not_modified(Route::StationOrders), 0);
+
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+
+    let after = harness.client
+        .market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap();
+
+    assert!(!is_stale(&after));
+    assert_eq!(prices(&before), prices(after.get_ref()));
+    assert!(harness.esi.not_modified(Route::StationOrders) >= 2);
+}
+
+#[tokio::test]
+async fn subscribers_share_refreshed_books() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.set_expires_in(1);
+
+    let mut first = harness.client
+        .subscribe_market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut second = harness.client
+        .subscribe_market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap()
+        .into_inner();
+
+    for subscription in [&mut first, &mut second] {
+        let initial = subscription.message().await.unwrap().unwrap();
+        assert_eq!(initial.market_orders.len(), 2);
+        assert!(initial.expires > 0);
+    }
+    assert_eq!(harness.esi.requests(Route::StationOrders), 2);
+
+    harness.esi.update_station_orders(|orders| {
+        orders.retain(|o| o["price"] != 7.5);
+    });
+    for subscription in [&mut first, &mut second] {
+        let update = tokio::time::timeout(
+            Duration::from_secs(5),
+            subscription.message(),
+        )
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(update.market_orders.len(), 1);
+        assert_eq!(update.market_orders[0].price, 7.6);
+    }
+    assert_eq!(harness.esi.requests(Route::StationOrders), 4);
+}
+
+#[tokio::test]
+async fn order_changes_compare_consecutive_books() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.set_expires_in(1);
+
+    let first = harness.client
+        .order_changes(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(first.from, 0);
+    assert!(first.new_orders.is_empty());
+
+    harness.esi.update_station_orders(|orders| {
+        orders.retain(|o| o["price"] != 7.5);
+        for order in orders.iter_mut().filter(|o| o["order_id"] == 6000000015i64) {
+            order["price"] = serde_json::json!(7.55);
+            order["volume_remain"] = serde_json::json!(40000);
+        }
+        orders.push(serde_json::json!({
+            "duration": 90,
+            "is_buy_order": false,
+            "issued": "2023-03-02T12:00:00Z",
+            "location_id": JITA_LOCATION_ID,
+            "min_volume": 1,
+            "order_id": 6000000100i64,
+            "price": 7.49,
+            "range": "region",
+            "system_id": 30000142,
+            "type_id": PYERITE,
+            "volume_remain": 5000,
+            "volume_total": 5000
+        }));
+    });
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+
+    let changes = harness.client
+        .order_changes(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(changes.from, first.to);
+    assert!(changes.to > changes.from);
+    assert_eq!(changes.new_orders.len(), 1);
+    assert_eq!(changes.new_orders[0].order_id, 6000000100);
+    assert_eq!(changes.removed_orders.len(), 1);
+    assert_eq!(changes.removed_orders[0].order_id, 6000000014);
+    assert_eq!(changes.price_changes.len(), 1);
+    assert_eq!(changes.price_changes[0].previous_price, 7.6);
+    assert_eq!(changes.volume_changes.len(), 1);
+    assert_eq!(changes.volume_changes[0].previous_volume_remain, 50000);
+    assert_eq!(changes.traded_volume, 100000 + 10000);
+}
+
+#[tokio::test]
+async fn price_history_records_refreshed_books() {
+    let mut harness = start(Options {
+        history: Some(
+            HistoryStore::open(":memory:", HistoryRetention::default()).unwrap(),
+        ),
+        ..Options::default()
+    })
+        .await;
+    let history_req = PriceHistoryReq {
+        type_id: PYERITE,
+        market: JITA.to_string(),
+        buy: false,
+        from: 0,
+        to: 0,
+        resolution: 0,
+    };
+
+    let empty = harness.client
+        .price_history(history_req.clone())
+        .await
+        .unwrap()
+        .into_inner();
+    let stats = harness.client
+        .market_stats(MarketStatsReq {
+            type_id: PYERITE,
+            market: JITA.to_string(),
+            side: MarketSide::Sell as i32,
+            percentile: 0.0,
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .sell
+        .unwrap();
+    // Summaries are written in the background
+    let mut recorded = PriceHistoryRep::default();
+    for _ in 0..50 {
+        recorded = harness.client
+            .price_history(history_req.clone())
+            .await
+            .unwrap()
+            .into_inner();
+        if !recorded.points.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    assert!(empty.points.is_empty());
+    assert_eq!(recorded.points.len(), 1);
+    let point = &recorded.points[0];
+    assert!(point.time > 0);
+    assert_eq!(point.best, stats.best);
+    assert_eq!(point.volume, stats.volume);
+    assert_eq!(point.order_count, stats.order_count);
+    assert_eq!(point.weighted_average, stats.weighted_average);
+    assert_eq!(point.median, stats.median);
+    assert_eq!(point.percentile, stats.percentile);
+}
+
+#[tokio::test]
+async fn price_history_requires_a_history_store() {
+    let mut harness = start(Options::default()).await;
+
+    let err = harness.client
+        .price_history(PriceHistoryReq {
+            type_id: PYERITE,
+            market: JITA.to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+
+    assert_eq!(err.code(), Code::FailedPrecondition);
+}
+
+#[test]
+fn history_compaction_downsamples_old_summaries() {
+    let history: HistoryStore = HistoryStore::open(
+        ":memory:",
+        HistoryRetention {
+            raw: 1000,
+            resolution: 100,
+            retention: 10000,
+        },
+    )
+        .unwrap();
+    let req: MarketOrdersReq = market_orders_req(JITA, PYERITE, false);
+    let summary = |best: f64| [(req.clone(), MarketSideStats {
+        order_count: 1,
+        volume: 10,
+        best: best,
+        weighted_average: best,
+        median: best,
+        percentile: best,
+    })];
+    // Dropped entirely, then averaged into one point, then kept as recorded
+    history.record(100, summary(1.0)).unwrap();
+    history.record(15010, summary(2.0)).unwrap();
+    history.record(15090, summary(4.0)).unwrap();
+    history.record(19500, summary(5.0)).unwrap();
+    history.record(19501, summary(6.0)).unwrap();
+
+    history.compact(20000).unwrap();
+    let points: Vec<PricePoint> = history
+        .query(JITA, PYERITE, false, 0, 20000, 0)
+        .unwrap();
+
+    let times: Vec<u64> = points.iter().map(|p| p.time).collect();
+    let bests: Vec<f64> = points.iter().map(|p| p.best).collect();
+    assert_eq!(times, vec![15000, 19500, 19501]);
+    assert_eq!(bests, vec![3.0, 5.0, 6.0]);
+}
+
+#[tokio::test]
+async fn prefetch_refreshes_books_after_expiry() {
+    let harness = start(Options {
+        prefetch: Prefetch {
+            enabled: true,
+            jitter: 0,
+            watchlist: vec![(THE_FORGE, PYERITE)],
+        },
+        ..Options::default()
+    })
+        .await;
+    // The first prefetch waits a second, which leaves time to shorten the
+    // snapshots it fetches
+    harness.esi.set_expires_in(1);
+
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let structure: usize = harness.esi.requests(Route::StructureOrders);
+    let station: usize = harness.esi.requests(Route::StationOrders);
+    tokio::time::sleep(Duration::from_millis(3000)).await;
+
+    assert!(structure > 0);
+    assert!(station > 0);
+    assert!(harness.esi.requests(Route::StructureOrders) > structure);
+    assert!(harness.esi.requests(Route::StationOrders) > station);
+}
+
+#[tokio::test]
+async fn stale_orders_are_served_while_refreshing() {
+    let mut harness = start(Options {
+        max_staleness: 60,
+        ..Options::default()
+    })
+        .await;
+    harness.esi.set_expires_in(1);
+
+    harness.client
+        .market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap();
+    harness.esi.update_station_orders(|orders| {
+        orders.retain(|o| o["price"] != 7.5);
+    });
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+
+    let stale = harness.client
+        .market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap();
+    assert!(is_stale(&stale));
+    assert_eq!(prices(stale.get_ref()), vec![7.5, 7.6]);
+
+    let mut refreshed = None;
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let rep = harness.client
+            .market_orders(market_orders_req(JITA, PYERITE, false))
+            .await
+            .unwrap();
+        if !is_stale(&rep) {
+            refreshed = Some(rep.into_inner());
+            break;
+        }
+    }
+    assert_eq!(prices(&refreshed.unwrap()), vec![7.6]);
+}
+
+#[tokio::test]
+async fn stale_orders_survive_esi_errors() {
+    let mut harness = start(Options {
+        max_staleness: 60,
+        ..Options::default()
+    })
+        .await;
+    harness.esi.set_expires_in(1);
+
+    harness.client
+        .market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    harness.esi.fail(Route::StationOrders, 503, 3);
+
+    for _ in 0..3 {
+        let rep = harness.client
+            .market_orders(market_orders_req(JITA, PYERITE, false))
+            .await
+            .unwrap();
+        assert_eq!(prices(rep.get_ref()), vec![7.5, 7.6]);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test]
+async fn esi_errors_become_statuses() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.fail(Route::StationOrders, 503, 3);
+    harness.esi.fail(Route::AdjustedPrices, 502, 3);
+
+    let orders = harness.client
+        .market_orders(market_orders_req(JITA, TRITANIUM, false))
+        .await
+        .unwrap_err();
+    let price = harness.client
+        .adjusted_price(AdjustedPriceReq { type_id: TRITANIUM })
+        .await
+        .unwrap_err();
+
+    assert_eq!(orders.code(), Code::Unavailable);
+    assert_eq!(orders.metadata().get("esi-status").unwrap(), "503");
+    assert_eq!(price.code(), Code::Unavailable);
+    assert_eq!(price.metadata().get("esi-status").unwrap(), "502");
+    assert!(price
+        .metadata()
+        .get("esi-body")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .contains("Injected failure")
+    );
+}
+
+#[tokio::test]
+async fn transient_esi_errors_are_retried_per_page() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.set_page_size(1);
+    harness.esi.fail(Route::StationOrders, 503, 2);
+    harness.esi.fail(Route::AdjustedPrices, 504, 2);
+
+    let orders = harness.client
+        .market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap()
+        .into_inner();
+    let price = harness.client
+        .adjusted_price(AdjustedPriceReq { type_id: TRITANIUM })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(prices(&orders), vec![7.5, 7.6]);
+    assert_eq!(price.adjusted_price, 4.62);
+    assert_eq!(harness.esi.requests(Route::AdjustedPrices), 3);
+}
+
+#[tokio::test]
+async fn broken_off_bodies_are_retried_per_page() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.set_page_size(1);
+    harness.esi.cut_bodies(Route::StationOrders, 1);
+
+    let rep = harness.client
+        .market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(prices(&rep), vec![7.5, 7.6]);
+}
+
+#[tokio::test]
+async fn retries_wait_for_retry_after() {
+    let mut harness = start(Options {
+        retry: Retry {
+            attempts: 3,
+            base_delay: 10,
+            max_delay: 2000,
+        },
+        ..Options::default()
+    })
+        .await;
+    harness.esi.fail_with_headers(
+        Route::AdjustedPrices,
+        503,
+        1,
+        &[("retry-after", "1")],
+    );
+
+    let started = Instant::now();
+    harness.client
+        .adjusted_price(AdjustedPriceReq { type_id: TRITANIUM })
+        .await
+        .unwrap();
+
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert_eq!(harness.esi.requests(Route::AdjustedPrices), 2);
+}
+
+#[tokio::test]
+async fn retry_after_is_capped_by_the_max_delay() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.fail_with_headers(
+        Route::AdjustedPrices,
+        503,
+        1,
+        &[("retry-after", "3600")],
+    );
+
+    let started = Instant::now();
+    harness.client
+        .adjusted_price(AdjustedPriceReq { type_id: TRITANIUM })
+        .await
+        .unwrap();
+
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(harness.esi.requests(Route::AdjustedPrices), 2);
+}
+
+#[tokio::test]
+async fn client_errors_are_not_retried() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.fail(Route::AdjustedPrices, 404, 1);
+
+    let price = harness.client
+        .adjusted_price(AdjustedPriceReq { type_id: TRITANIUM })
+        .await
+        .unwrap_err();
+
+    assert_eq!(price.code(), Code::NotFound);
+    assert_eq!(harness.esi.requests(Route::AdjustedPrices), 1);
+}
+
+#[tokio::test]
+async fn structure_orders_authenticate_with_sso() {
+    let mut harness = start(Options::default()).await;
+
+    let sell = harness.client
+        .market_orders(market_orders_req(KEEPSTAR, TRITANIUM, false))
+        .await
+        .unwrap()
+        .into_inner();
+    let buy = harness.client
+        .market_orders(market_orders_req(KEEPSTAR, TRITANIUM, true))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(prices(&sell), vec![5.3, 5.4]);
+    assert_eq!(prices(&buy), vec![4.7]);
+    assert_eq!(harness.esi.requests(Route::Token), 1);
+    assert_eq!(harness.esi.requests(Route::Jwks), 1);
+}
+
+#[tokio::test]
+async fn access_tokens_are_reused_until_expiry() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.set_expires_in(1);
+
+    harness.client
+        .market_orders(market_orders_req(KEEPSTAR, TRITANIUM, false))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    harness.client
+        .market_orders(market_orders_req(KEEPSTAR, TRITANIUM, false))
+        .await
+        .unwrap();
+
+    assert_eq!(harness.esi.requests(Route::StructureOrders), 4);
+    assert_eq!(harness.esi.requests(Route::Token), 1);
+}
+
+#[tokio::test]
+async fn access_tokens_are_renewed_within_margin() {
+    // Tokens last 1199 seconds, so they are only fresh for about 2 seconds
+    let mut harness = start(Options {
+        token_refresh_margin: 1197,
+        ..Options::default()
+    })
+        .await;
+    harness.esi.set_expires_in(1);
+
+    harness.client
+        .market_orders(market_orders_req(KEEPSTAR, TRITANIUM, false))
+        .await
+        .unwrap();
+    assert_eq!(harness.esi.requests(Route::Token), 1);
+
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    harness.client
+        .market_orders(market_orders_req(KEEPSTAR, TRITANIUM, false))
+        .await
+        .unwrap();
+    assert_eq!(harness.esi.requests(Route::Token), 2);
+}
+
+#[tokio::test]
+async fn rotated_refresh_tokens_are_stored_and_used() {
+    let mut harness = start(Options {
+        token_refresh_margin: 1197,
+        ..Options::default()
+    })
+        .await;
+    harness.esi.rotate_refresh_tokens(true);
+    harness.esi.set_expires_in(1);
+
+    harness.client
+        .market_orders(market_orders_req(KEEPSTAR, TRITANIUM, false))
+        .await
+        .unwrap();
+    assert_eq!(
+        harness.tokens.get(REFRESH_TOKEN).as_deref(),
+        Some("rotated-1"),
+    );
+
+    // The configured token has been revoked, so this only succeeds if the
+    // rotated one is used
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    harness.client
+        .market_orders(market_orders_req(KEEPSTAR, TRITANIUM, false))
+        .await
+        .unwrap();
+    assert_eq!(
+        harness.tokens.get(REFRESH_TOKEN).as_deref(),
+        Some("rotated-2"),
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn stored_refresh_tokens_are_private() {
+    use std::os::unix::fs::PermissionsExt;
+    use weve_market::token_store::{FileTokenStore, TokenStore};
+
+    let path: std::path::PathBuf = std::env::temp_dir()
+        .join(format!("weve_market_{}_tokens.json", std::process::id()));
+    let store: FileTokenStore = FileTokenStore::new(&path);
+    store.save(REFRESH_TOKEN, "rotated-1").unwrap();
+    let mode: u32 = std::fs::metadata(&path).unwrap().permissions().mode();
+    let tokens = store.load().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(mode & 0o777, 0o600);
+    assert_eq!(tokens[REFRESH_TOKEN], "rotated-1");
+}
+
+#[tokio::test]
+async fn tokens_without_structure_scope_are_refused() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.set_scopes(&["publicData"]);
+
+    let status = harness.client
+        .market_orders(market_orders_req(KEEPSTAR, TRITANIUM, false))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    assert_eq!(harness.esi.requests(Route::StructureOrders), 0);
+
+    let auth = harness.client
+        .structure_market_auth(StructureMarketAuthReq {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(auth.markets.len(), 1);
+    assert!(!auth.markets[0].authorized);
+    assert!(auth.markets[0].error.contains(STRUCTURE_SCOPE));
+    // The rejected token is not refreshed again for every request
+    assert_eq!(harness.esi.requests(Route::Token), 1);
+}
+
+#[tokio::test]
+async fn signing_key_failures_are_not_refreshed_every_request() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.fail(Route::Jwks, 503, 10);
+
+    for _ in 0..2 {
+        let status = harness.client
+            .market_orders(market_orders_req(KEEPSTAR, TRITANIUM, false))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+    }
+
+    assert_eq!(harness.esi.requests(Route::Token), 1);
+    assert_eq!(harness.esi.requests(Route::StructureOrders), 0);
+}
+
+#[tokio::test]
+async fn structure_market_auth_reports_character() {
+    let mut harness = start(Options::default()).await;
+
+    let auth = harness.client
+        .structure_market_auth(StructureMarketAuthReq {})
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(auth.markets.len(), 1);
+    let market = &auth.markets[0];
+    assert_eq!(market.market, KEEPSTAR);
+    assert_eq!(market.location_id, KEEPSTAR_LOCATION_ID);
+    assert!(market.authorized);
+    assert_eq!(market.character_id, CHARACTER_ID);
+    assert_eq!(market.character_name, CHARACTER_NAME);
+    assert_eq!(market.scopes, vec![STRUCTURE_SCOPE.to_string()]);
+}
+
+#[tokio::test]
+async fn detailed_orders_carry_every_esi_field() {
+    let mut harness = start(Options::default()).await;
+
+    let station = harness.client
+        .market_orders_detailed(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap()
+        .into_inner();
+    let structure = harness.client
+        .market_orders_detailed(market_orders_req(KEEPSTAR, PYERITE, true))
+        .await
+        .unwrap()
+        .into_inner();
+    let summary = harness.client
+        .market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap()
+        .into_inner();
+
+    let mut order = station.market_orders
+        .into_iter()
+        .find(|o| o.price == 7.5)
+        .unwrap();
+    assert_eq!(order.order_id, 6000000014);
+    assert_eq!(order.type_id, PYERITE);
+    assert_eq!(order.location_id, JITA_LOCATION_ID);
+    assert_eq!(order.system_id, 30000142);
+    assert!(!order.is_buy_order);
+    assert_eq!(order.volume_remain, 100000);
+    assert_eq!(order.volume_total, 100000);
+    assert_eq!(order.min_volume, 1);
+    assert_eq!(order.issued, "2023-03-01T12:00:00Z");
+    assert_eq!(order.duration, 90);
+    assert_eq!(order.range, "region");
+
+    assert_eq!(structure.market_orders.len(), 1);
+    order = structure.market_orders[0].clone();
+    assert_eq!(order.order_id, 6000000020);
+    assert_eq!(order.location_id, KEEPSTAR_LOCATION_ID);
+    assert_eq!(order.system_id, 0);
+    assert!(order.is_buy_order);
+
+    assert_eq!(prices(&summary), vec![7.5, 7.6]);
+    assert_eq!(harness.esi.requests(Route::StationOrders), 2);
+}
+
+#[tokio::test]
+async fn batch_orders_fetch_each_region_type_once() {
+    let mut harness = start(Options::default()).await;
+
+    let rep = harness.client
+        .batch_market_orders(BatchMarketOrdersReq {
+            type_ids: vec![TRITANIUM, PYERITE, PYERITE],
+            markets: vec![
+                JITA.to_string(),
+                JITA_NEIGHBOUR.to_string(),
+                "nowhere".to_string(),
+            ],
+            buy: vec![false],
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(rep.items.len(), 6);
+    let item = |market: &str, type_id: i32| rep.items
+        .iter()
+        .find(|i| {
+            let req = i.req.as_ref().unwrap();
+            req.market == market && req.type_id == type_id
+        })
+        .unwrap();
+    assert_eq!(
+        prices(&MarketOrdersRep {
+            market_orders: item(JITA, PYERITE).market_orders.clone(),
+        }),
+        vec![7.5, 7.6],
+    );
+    assert_eq!(item(JITA, PYERITE).code, 0);
+    assert!(!item(JITA_NEIGHBOUR, TRITANIUM).market_orders.is_empty());
+    assert_eq!(item("nowhere", TRITANIUM).code, Code::NotFound as i32);
+    assert!(item("nowhere", PYERITE).error.contains("nowhere"));
+
+    // A HEAD and a single page for each type, shared by both stations
+    assert_eq!(harness.esi.requests(Route::StationOrders), 4);
+}
+
+#[tokio::test]
+async fn market_stats_summarize_the_cached_book() {
+    let mut harness = start(Options::default()).await;
+
+    let sell = harness.client
+        .market_stats(MarketStatsReq {
+            type_id: PYERITE,
+            market: JITA.to_string(),
+            side: MarketSide::Sell as i32,
+            percentile: 80.0,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let both = harness.client
+        .market_stats(MarketStatsReq {
+            type_id: PYERITE,
+            market: KEEPSTAR.to_string(),
+            side: MarketSide::Both as i32,
+            percentile: 0.0,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert!(sell.buy.is_none());
+    let stats = sell.sell.unwrap();
+    assert_eq!(stats.order_count, 2);
+    assert_eq!(stats.volume, 150000);
+    assert_eq!(stats.best, 7.5);
+    assert!((stats.weighted_average - 7.5333).abs() < 0.0001);
+    assert_eq!(stats.median, 7.5);
+    assert!((stats.percentile - 7.5167).abs() < 0.0001);
+    assert_eq!(sell.spread, 0.0);
+
+    assert_eq!(both.buy.unwrap().best, 7.2);
+    assert_eq!(both.sell.unwrap().percentile, 8.1);
+    assert!((both.spread - 0.9).abs() < 0.0001);
+}
+
+#[tokio::test]
+async fn fill_price_walks_the_book() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.update_station_orders(|orders| {
+        orders.push(serde_json::json!({
+            "duration": 90,
+            "is_buy_order": true,
+            "issued": "2023-03-01T12:00:00Z",
+            "location_id": JITA_LOCATION_ID,
+            "min_volume": 50000,
+            "order_id": 6000000099i64,
+            "price": 7.4,
+            "range": "station",
+            "system_id": 30000142,
+            "type_id": PYERITE,
+            "volume_remain": 60000,
+            "volume_total": 60000
+        }));
+    });
+
+    let buying = harness.client
+        .fill_price(FillPriceReq {
+            type_id: PYERITE,
+            market: JITA.to_string(),
+            buy: false,
+            quantity: 120000,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let short = harness.client
+        .fill_price(FillPriceReq {
+            type_id: PYERITE,
+            market: JITA.to_string(),
+            buy: false,
+            quantity: 200000,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let below_min_volume = harness.client
+        .fill_price(FillPriceReq {
+            type_id: PYERITE,
+            market: JITA.to_string(),
+            buy: true,
+            quantity: 1000,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert!((buying.total - 902000.0).abs() < 0.01);
+    assert!((buying.average_price - 7.5167).abs() < 0.0001);
+    assert_eq!(buying.worst_price, 7.6);
+    assert_eq!(buying.filled, 120000);
+    assert_eq!(buying.unfilled, 0);
+    assert_eq!(buying.orders_filled, 2);
+
+    assert_eq!(short.filled, 150000);
+    assert_eq!(short.unfilled, 50000);
+
+    assert_eq!(below_min_volume.filled, 0);
+    assert_eq!(below_min_volume.unfilled, 1000);
+}
+
+fn remote_buy_order(
+    order_id: i64,
+    location_id: i64,
+    system_id: i32,
+    range: &str,
+    price: f64,
+) -> serde_json::Value {
+    serde_json::json!({
+        "duration": 90,
+        "is_buy_order": true,
+        "issued": "2023-03-01T12:00:00Z",
+        "location_id": location_id,
+        "min_volume": 1,
+        "order_id": order_id,
+        "price": price,
+        "range": range,
+        "system_id": system_id,
+        "type_id": PYERITE,
+        "volume_remain": 1000,
+        "volume_total": 1000
+    })
+}
+
+#[tokio::test]
+async fn remote_buy_orders_in_range_are_included() {
+    let mut harness = start(Options {
+        buy_range: Some(BuyRange {
+            jumps: HashMap::from([
+                (30000142, vec![30000144]),
+                (30000144, vec![30000142, 30000145]),
+                (30000145, vec![30000144]),
+            ]),
+            station_systems: HashMap::from([(JITA_LOCATION_ID, 30000142)]),
+        }),
+        ..Options::default()
+    })
+        .await;
+    harness.esi.update_station_orders(|orders| {
+        orders.push(remote_buy_order(7000000001, 60000001, 30000144, "1", 7.0));
+        orders.push(remote_buy_order(7000000002, 60000002, 30000144, "station", 6.9));
+        orders.push(remote_buy_order(7000000003, 60000003, 30002187, "region", 6.8));
+        orders.push(remote_buy_order(7000000004, 60000004, 30000145, "1", 6.7));
+        orders.push(remote_buy_order(7000000005, 60000005, 30000142, "solarsystem", 6.6));
+    });
+
+    let jita = harness.client
+        .market_orders_detailed(market_orders_req(JITA, PYERITE, true))
+        .await
+        .unwrap()
+        .into_inner();
+    let neighbour = harness.client
+        .market_orders(market_orders_req(JITA_NEIGHBOUR, PYERITE, true))
+        .await
+        .unwrap()
+        .into_inner();
+    let fill = harness.client
+        .fill_price(FillPriceReq {
+            type_id: PYERITE,
+            market: JITA.to_string(),
+            buy: true,
+            quantity: 1500,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    let mut remote: Vec<i64> = jita.market_orders
+        .iter()
+        .filter(|o| o.remote)
+        .map(|o| o.order_id)
+        .collect();
+    remote.sort();
+    assert_eq!(remote, vec![7000000001, 7000000003, 7000000005]);
+    assert!(neighbour.market_orders.iter().all(|o| !o.remote));
+    assert_eq!(fill.filled, 1500);
+    assert_eq!(fill.worst_price, 6.8);
+}
+
+#[tokio::test]
+async fn unknown_market_is_not_found() {
+    let mut harness = start(Options::default()).await;
+
+    let status = harness.client
+        .market_orders(market_orders_req("jitaa", TRITANIUM, false))
+        .await
+        .unwrap_err();
+
+    assert_eq!(status.code(), Code::NotFound);
+    assert!(status.message().contains("jitaa"));
+}
+
+#[tokio::test]
+async fn unknown_ids_are_empty_when_not_strict() {
+    let mut harness = start(Options {
+        strict: false,
+        ..Options::default()
+    })
+        .await;
+
+    let orders = harness.client
+        .market_orders(market_orders_req("jitaa", TRITANIUM, false))
+        .await
+        .unwrap()
+        .into_inner();
+    let price = harness.client
+        .adjusted_price(AdjustedPriceReq { type_id: 99999 })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert!(orders.market_orders.is_empty());
+    assert_eq!(price.adjusted_price, 0.0);
+}
+
+#[tokio::test]
+async fn adjusted_prices_are_fetched_once() {
+    let mut harness = start(Options::default()).await;
+
+    let tritanium = harness.client
+        .adjusted_price(AdjustedPriceReq { type_id: TRITANIUM })
+        .await
+        .unwrap()
+        .into_inner();
+    let pyerite = harness.client
+        .adjusted_price(AdjustedPriceReq { type_id: PYERITE })
+        .await
+        .unwrap()
+        .into_inner();
+    let unknown = harness.client
+        .adjusted_price(AdjustedPriceReq { type_id: 99999 })
+        .await
+        .unwrap_err();
+
+    assert_eq!(tritanium.adjusted_price, 4.62);
+    assert_eq!(pyerite.adjusted_price, 9.71);
+    assert_eq!(unknown.code(), Code::NotFound);
+    assert!(unknown.message().contains("99999"));
+    assert_eq!(harness.esi.requests(Route::AdjustedPrices), 1);
+}
+
+#[tokio::test]
+async fn system_indices_are_fetched_once() {
+    let mut harness = start(Options::default()).await;
+
+    let jita = harness.client
+        .system_index(SystemIndexReq { system_id: 30000142 })
+        .await
+        .unwrap()
+        .into_inner();
+    let unknown = harness.client
+        .system_index(SystemIndexReq { system_id: 1 })
+        .await
+        .unwrap_err();
+
+    assert_eq!(jita.manufacturing, 0.0113);
+    assert_eq!(jita.research_te, 0.0213);
+    assert_eq!(jita.research_me, 0.0313);
+    assert_eq!(jita.copying, 0.0413);
+    assert_eq!(jita.invention, 0.0513);
+    assert_eq!(jita.reactions, 0.0613);
+    assert_eq!(unknown.code(), Code::NotFound);
+    assert_eq!(harness.esi.requests(Route::SystemIndices), 1);
+}
+
+#[tokio::test]
+async fn requests_pause_when_error_budget_runs_low() {
+    let mut harness = start(Options::default()).await;
+    harness.esi.set_error_limit(5, 2);
+
+    harness.client
+        .adjusted_price(AdjustedPriceReq { type_id: TRITANIUM })
+        .await
+        .unwrap();
+    let status = harness.client
+        .esi_status(EsiStatusReq {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(status.error_limit_known);
+    assert_eq!(status.error_limit_remain, 5);
+    assert!(status.paused);
+    assert_eq!(status.delayed_requests, 0);
+
+    let started = Instant::now();
+    harness.client
+        .market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap();
+    let status = harness.client
+        .esi_status(EsiStatusReq {})
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert!(status.delayed_requests >= 1);
+    assert_eq!(status.requests_in_flight, 0);
+}
+
+#[tokio::test]
+async fn adjusted_prices_return_the_whole_snapshot() {
+    let mut harness = start(Options::default()).await;
+
+    let all = harness.client
+        .adjusted_prices(AdjustedPricesReq { type_ids: vec![] })
+        .await
+        .unwrap()
+        .into_inner();
+    let some = harness.client
+        .adjusted_prices(AdjustedPricesReq { type_ids: vec![TRITANIUM, 99999] })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(all.prices.len(), 3);
+    assert_eq!(all.prices[&TRITANIUM].adjusted_price, 4.62);
+    assert_eq!(all.prices[&TRITANIUM].average_price, Some(4.88));
+    assert_eq!(all.prices[&36].average_price, None);
+    assert!(all.expires > 0);
+    assert_eq!(some.prices.len(), 1);
+    assert_eq!(some.prices[&TRITANIUM].average_price, Some(4.88));
+    assert_eq!(some.expires, all.expires);
+    assert_eq!(harness.esi.requests(Route::AdjustedPrices), 1);
+}
+
+#[tokio::test]
+async fn system_indices_return_the_whole_snapshot() {
+    let mut harness = start(Options::default()).await;
+
+    let all = harness.client
+        .system_indices(SystemIndicesReq { system_ids: vec![] })
+        .await
+        .unwrap()
+        .into_inner();
+    let some = harness.client
+        .system_indices(SystemIndicesReq { system_ids: vec![30000142] })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(all.indices.len(), 2);
+    assert!(all.indices.contains_key(&30002187));
+    assert_eq!(some.indices.len(), 1);
+    assert_eq!(some.indices[&30000142].manufacturing, 0.0113);
+    assert!(some.expires > 0);
+    assert_eq!(harness.esi.requests(Route::SystemIndices), 1);
+}
+
+#[tokio::test]
+async fn market_history_is_cached_per_region_and_type() {
+    let mut harness = start(Options::default()).await;
+
+    let by_market = harness.client
+        .market_history(MarketHistoryReq {
+            type_id: TRITANIUM,
+            market: JITA.to_string(),
+            region_id: 0,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let by_region = harness.client
+        .market_history(MarketHistoryReq {
+            type_id: TRITANIUM,
+            market: String::new(),
+            region_id: THE_FORGE,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let structure = harness.client
+        .market_history(MarketHistoryReq {
+            type_id: TRITANIUM,
+            market: KEEPSTAR.to_string(),
+            region_id: 0,
+        })
+        .await
+        .unwrap_err();
+
+    assert_eq!(by_market.days.len(), 2);
+    assert_eq!(by_market.days[1].date, "2023-03-02");
+    assert_eq!(by_market.days[1].average, 4.83);
+    assert_eq!(by_market.days[1].highest, 5.0);
+    assert_eq!(by_market.days[1].lowest, 4.62);
+    assert_eq!(by_market.days[1].volume, 9012388755);
+    assert_eq!(by_market.days[1].order_count, 9877);
+    assert!(by_market.expires > 0);
+    assert_eq!(by_region, by_market);
+    assert_eq!(structure.code(), Code::InvalidArgument);
+    assert_eq!(harness.esi.requests(Route::MarketHistory), 1);
+}
+
+#[tokio::test]
+async fn market_history_rejects_unknown_regions_and_types() {
+    let mut harness = start(Options::default()).await;
+    let history_req = |type_id: i32, region_id: i32| MarketHistoryReq {
+        type_id: type_id,
+        market: String::new(),
+        region_id: region_id,
+    };
+
+    let unconfigured = harness.client
+        .market_history(history_req(TRITANIUM, 10000043))
+        .await
+        .unwrap_err();
+    let invalid = harness.client
+        .market_history(history_req(-1, THE_FORGE))
+        .await
+        .unwrap_err();
+    assert_eq!(harness.esi.requests(Route::MarketHistory), 0);
+    let unknown = harness.client
+        .market_history(history_req(99999, THE_FORGE))
+        .await
+        .unwrap_err();
+    let unknown_again = harness.client
+        .market_history(history_req(99999, THE_FORGE))
+        .await
+        .unwrap_err();
+
+    assert_eq!(unconfigured.code(), Code::NotFound);
+    assert_eq!(invalid.code(), Code::InvalidArgument);
+    assert_eq!(unknown.code(), Code::NotFound);
+    assert_eq!(unknown_again.code(), Code::NotFound);
+    assert_eq!(harness.esi.requests(Route::MarketHistory), 1);
+}
+
+#[tokio::test]
+async fn bulk_regions_serve_every_type_from_one_fetch() {
+    let mut harness = start(Options {
+        bulk_regions: vec![THE_FORGE],
+        ..Options::default()
+    })
+        .await;
+
+    let pyerite = harness.client
+        .market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap()
+        .into_inner();
+    let fetches: usize = harness.esi.requests(Route::StationOrders);
+    let tritanium = harness.client
+        .market_orders(market_orders_req(JITA_NEIGHBOUR, TRITANIUM, false))
+        .await
+        .unwrap()
+        .into_inner();
+    let missing = harness.client
+        .market_orders(market_orders_req(JITA, 99999, true))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(prices(&pyerite), vec![7.5, 7.6]);
+    assert_eq!(prices(&tritanium), vec![5.0, 5.03]);
+    assert!(missing.market_orders.is_empty());
+    assert_eq!(harness.esi.requests(Route::StationOrders), fetches);
+    assert_eq!(fetches, 2);
+}
+
+#[tokio::test]
+async fn concurrent_requests_share_one_bulk_walk() {
+    let harness = start(Options {
+        bulk_regions: vec![THE_FORGE],
+        ..Options::default()
+    })
+        .await;
+    let (mut pyerite_client, mut tritanium_client) =
+        (harness.client.clone(), harness.client.clone());
+
+    let (pyerite, tritanium) = tokio::join!(
+        pyerite_client.market_orders(market_orders_req(JITA, PYERITE, false)),
+        tritanium_client.market_orders(
+            market_orders_req(JITA_NEIGHBOUR, TRITANIUM, false),
+        ),
+    );
+
+    assert_eq!(prices(&pyerite.unwrap().into_inner()), vec![7.5, 7.6]);
+    assert_eq!(prices(&tritanium.unwrap().into_inner()), vec![5.0, 5.03]);
+    // One HEAD and one page
+    assert_eq!(harness.esi.requests(Route::StationOrders), 2);
+}
+
+#[tokio::test]
+async fn concurrent_requests_share_a_failed_bulk_walk() {
+    let harness = start(Options {
+        bulk_regions: vec![THE_FORGE],
+        ..Options::default()
+    })
+        .await;
+    harness.esi.fail(Route::StationOrders, 404, 1);
+    let (mut pyerite_client, mut tritanium_client) =
+        (harness.client.clone(), harness.client.clone());
+
+    let (pyerite, tritanium) = tokio::join!(
+        pyerite_client.market_orders(market_orders_req(JITA, PYERITE, false)),
+        tritanium_client.market_orders(
+            market_orders_req(JITA_NEIGHBOUR, TRITANIUM, false),
+        ),
+    );
+
+    assert_eq!(pyerite.unwrap_err().code(), Code::NotFound);
+    assert_eq!(tritanium.unwrap_err().code(), Code::NotFound);
+    assert_eq!(harness.esi.requests(Route::StationOrders), 1);
+
+    // The failed walk is not kept around for later requests
+    let rep = harness.client
+        .clone()
+        .market_orders(market_orders_req(JITA, PYERITE, false))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(prices(&rep), vec![7.5, 7.6]);
+}